@@ -0,0 +1,69 @@
+#!/usr/bin/env bash
+# Times examples/bigdict.pppl: filling a dict with 100,000 keys, then a few
+# steps of `mark`, each of which evaluates the guards of all 100,000
+# instances against that dict.
+#
+#     scripts/bench-bigdict.sh [REV...]
+#
+# This benchmarks the working tree, and then each REV, built from a clean
+# checkout, to compare against.  For example, `93c5520^` is the last commit
+# before dicts were shared rather than copied.  Each run starts from a fresh
+# pmemory in a temporary $HOME.
+#
+# STEPS (default 5) is how many steps of `mark` to time.  TIMEOUT (default
+# 30m) is passed to each phase as --timeout, which is only checked between
+# steps, and old revisions can take minutes per step.
+
+set -eu
+
+STEPS=${STEPS:-5}
+TIMEOUT=${TIMEOUT:-30m}
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+example="$root/examples/bigdict.pppl"
+work=$(mktemp -d)
+trap 'rm -rf "$work"; git -C "$root" worktree prune' EXIT
+
+now() {
+    date +%s.%N
+}
+
+# Runs `ppppl $2...` with the binary $1, and prints how long it took.
+timed() {
+    local bin=$1 start status
+    shift
+    start=$(now)
+    status=0
+    HOME="$work/home" "$bin" "$@" >> "$work/output" 2>&1 || status=$?
+    awk -v start="$start" -v end="$(now)" 'BEGIN { printf "%8.2fs", end - start }'
+    # NOTE: --until exits 0, --max-steps 3, and --timeout 4.
+    case $status in
+        0|3) echo ;;
+        4) echo "  (timed out)" ;;
+        *) echo "  (failed with exit code $status; see below)"; tail -n 20 "$work/output"; exit 1 ;;
+    esac
+}
+
+bench() {
+    local name=$1 bin=$2
+    rm -rf "$work/home" "$work/output"
+    mkdir "$work/home"
+    echo "$name"
+    printf '  fill 100,000 keys:    '
+    timed "$bin" run --until 'n > SIZE' --timeout "$TIMEOUT" "$example"
+    printf '  %2d steps of mark:     ' "$STEPS"
+    timed "$bin" run --max-steps "$STEPS" --timeout "$TIMEOUT"
+}
+
+(cd "$root" && cargo build --release --quiet)
+bench "working tree" "$root/target/release/ppppl"
+
+for rev in "$@"; do
+    checkout="$work/checkout"
+    git -C "$root" worktree add --quiet --detach "$checkout" "$rev"
+    # NOTE: Cargo.lock isn't checked in, so build with the same dependencies.
+    if [ -e "$root/Cargo.lock" ]; then cp "$root/Cargo.lock" "$checkout/"; fi
+    (cd "$checkout" && CARGO_TARGET_DIR="$work/target" cargo build --release --quiet)
+    bench "$rev ($(git -C "$root" rev-parse --short "$rev"))" "$work/target/release/ppppl"
+    git -C "$root" worktree remove --force "$checkout"
+done
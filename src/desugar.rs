@@ -0,0 +1,766 @@
+// Parse-time rewriting of sugar (templates, processes, constants)
+// into ordinary blocks.
+//
+// Everything here runs inside the parser, so the rest of the system only ever
+// sees a `Module` made of plain `Block`s.  Expanded blocks keep the source
+// spans of the template they came from.
+
+use crate::syntax::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+
+/// Hidden key in pmemory under which every process keeps its program
+/// counter.  Like `eval::TIMERS_KEY`, it is not a valid PPPL name.
+const PC_KEY: &str = "@pc";
+
+/// The program counter of a process that has run off the end of its body.
+const DONE_LABEL: &str = "@done";
+
+type Substitution<A> = BTreeMap<String, Exp<A>>;
+
+type Constants = BTreeMap<String, Value>;
+
+// The names that appear in `e` outside of any `choose` that binds them.
+fn free_names<A>(e: &Exp<A>, out: &mut BTreeSet<String>) {
+    match e {
+        Exp::Name(_, n) => { out.insert(n.clone()); }
+        Exp::Root(_) | Exp::Literal(_, _) => { }
+        Exp::Unary(_, _, e1) => { free_names(e1, out); }
+        Exp::Binary(_, _, e1, e2) => { free_names(e1, out); free_names(e2, out); }
+        Exp::Ternary(_, _, e1, e2, e3) => { free_names(e1, out); free_names(e2, out); free_names(e3, out); }
+        Exp::Call(_, _, call_args) => { for arg in call_args { free_names(arg, out); } }
+        Exp::Choose(_, k, e1, cond) => {
+            free_names(e1, out);
+            if let Some(cond) = cond {
+                let mut inner = BTreeSet::new();
+                free_names(cond, &mut inner);
+                inner.remove(k);
+                out.extend(inner);
+            }
+        }
+    }
+}
+
+/// The substitution to use under something that binds `name` (a block
+/// parameter or a `choose` variable), and the name it should bind instead.
+/// `name` shadows the template parameter of the same name, and if one of the
+/// arguments mentions `name`, the binder is renamed so that it doesn't
+/// capture it.
+fn bind<A: Clone>(a: &A, name: &str, args: &Substitution<A>) -> (String, Substitution<A>) {
+    let mut inner = args.clone();
+    inner.remove(name);
+    let mut mentioned = BTreeSet::new();
+    for arg in inner.values() {
+        free_names(arg, &mut mentioned);
+    }
+    if !mentioned.contains(name) {
+        return (name.to_string(), inner);
+    }
+
+    // NOTE: names in the source can't contain `'`, so the new name can't
+    // clash with anything the program says.
+    let mut fresh = format!("{}'", name);
+    while mentioned.contains(&fresh) {
+        fresh.push('\'');
+    }
+    inner.insert(name.to_string(), Exp::Name(a.clone(), fresh.clone()));
+    return (fresh, inner);
+}
+
+fn subst_exp<A: Clone>(e: &Exp<A>, args: &Substitution<A>) -> Exp<A> {
+    match e {
+        Exp::Name(_, n) => match args.get(n) {
+            Some(arg) => arg.clone(),
+            None => e.clone(),
+        },
+        Exp::Root(_) | Exp::Literal(_, _) => e.clone(),
+        Exp::Unary(a, op, e1) => Exp::Unary(a.clone(), *op, Box::new(subst_exp(e1, args))),
+        Exp::Binary(a, op, e1, e2) => Exp::Binary(a.clone(), *op,
+            Box::new(subst_exp(e1, args)),
+            Box::new(subst_exp(e2, args))),
+        Exp::Ternary(a, op, e1, e2, e3) => Exp::Ternary(a.clone(), *op,
+            Box::new(subst_exp(e1, args)),
+            Box::new(subst_exp(e2, args)),
+            Box::new(subst_exp(e3, args))),
+        Exp::Call(a, f, call_args) => Exp::Call(a.clone(), *f,
+            call_args.iter().map(|arg| subst_exp(arg, args)).collect()),
+        Exp::Choose(a, k, e1, cond) => {
+            let (k, inner) = bind(a, k, args);
+            Exp::Choose(a.clone(), k,
+                Box::new(subst_exp(e1, args)),
+                cond.as_ref().map(|c| Box::new(subst_exp(c, &inner))))
+        }
+    }
+}
+
+fn exp_to_lval<A: Clone>(e: &Exp<A>) -> Result<LVal<A>, String> {
+    match e {
+        Exp::Root(a) => Ok(LVal::Root(a.clone())),
+        Exp::Name(a, n) => Ok(LVal::Name(a.clone(), n.clone())),
+        Exp::Binary(a, BinaryOp::INDEX, e1, e2) => Ok(LVal::Index(a.clone(), Box::new(exp_to_lval(e1)?), e2.clone())),
+        _ => Err("a template argument that is assigned to must be a name or an index expression".to_string()),
+    }
+}
+
+fn subst_lval<A: Clone>(lv: &LVal<A>, args: &Substitution<A>) -> Result<LVal<A>, String> {
+    match lv {
+        LVal::Name(_, n) => match args.get(n) {
+            Some(arg) => exp_to_lval(arg),
+            None => Ok(lv.clone()),
+        },
+        LVal::Root(_) => Ok(lv.clone()),
+        LVal::Index(a, x, i) => Ok(LVal::Index(a.clone(),
+            Box::new(subst_lval(x, args)?),
+            Box::new(subst_exp(i, args)))),
+    }
+}
+
+fn subst_block<A: Clone>(b: &Block<A>, name: String, args: &Substitution<A>) -> Result<Block<A>, String> {
+    // Block parameters shadow template parameters.  Each block parameter is
+    // in scope for the ones after it (see `eval::instantiate_params`).
+    let mut args = args.clone();
+    let mut parameters = Vec::new();
+    for (p, e) in &b.parameters {
+        let e = subst_exp(e, &args);
+        let (p, inner) = bind(&b.annotation, p, &args);
+        parameters.push((p, e));
+        args = inner;
+    }
+
+    let mut assignments = Vec::new();
+    for (lv, e) in &b.assignments {
+        assignments.push((subst_lval(lv, &args)?, subst_exp(e, &args)));
+    }
+
+    return Ok(Block {
+        annotation: b.annotation.clone(),
+        attributes: b.attributes.iter().map(|attr| match attr {
+            Attribute::Every(a, period) => Attribute::Every(a.clone(), subst_exp(period, &args)),
+            Attribute::Priority(a, p) => Attribute::Priority(a.clone(), subst_exp(p, &args)),
+            Attribute::Weight(_, _) | Attribute::Disabled(_) => attr.clone(),
+        }).collect(),
+        name: name,
+        parameters: parameters,
+        guards: b.guards.iter().map(|g| subst_exp(g, &args)).collect(),
+        assignments: assignments,
+    });
+}
+
+/// Constant names are written in capitals (`MAX`, `RETRY_LIMIT`), so that
+/// they stand out from pmemory keys.
+fn is_constant_name(n: &str) -> bool {
+    return n.starts_with(|c: char| c.is_ascii_uppercase()) && !n.contains(|c: char| c.is_ascii_lowercase());
+}
+
+/// If `n` looks like a constant (`MAXX`) or a variant of a declared enum
+/// (`State.Idel`) but isn't one, the error to report.  Any other undeclared
+/// name is a pmemory key.
+fn check_undeclared(n: &str, constants: &Constants) -> Result<(), String> {
+    if is_constant_name(n) {
+        return Err(format!("undefined constant `{}`; write `.{}` to mean the memory key", n, n));
+    }
+    if let Some((e, v)) = n.rsplit_once('.') {
+        let prefix = format!("{}.", e);
+        if constants.keys().any(|k| k.starts_with(&prefix)) {
+            return Err(format!("enum `{}` has no variant `{}`", e, v));
+        }
+    }
+    return Ok(());
+}
+
+fn constant_value<A>(e: &Exp<A>, constants: &Constants) -> Result<Value, String> {
+    match e {
+        Exp::Literal(_, v) => Ok(v.clone()),
+        Exp::Unary(_, UnaryOp::NEGATE, e1) => match constant_value(e1, constants)? {
+            Value::Int(i) => Ok(Value::Int(-i)),
+            v => Err(format!("cannot negate {}", v)),
+        },
+        Exp::Name(_, n) => match constants.get(n) {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("undefined constant `{}`", n)),
+        },
+        _ => Err("the value of a constant must be a literal or another constant".to_string()),
+    }
+}
+
+fn declare_constant(constants: &mut Constants, name: String, value: Value) -> Result<(), String> {
+    // NOTE: a file imported under two different aliases declares its
+    // constants twice, which is fine as long as they agree.
+    match constants.get(&name) {
+        Some(other) if *other != value => {
+            return Err(format!("constant `{}` is declared as both {} and {}", name, other, value));
+        }
+        _ => { constants.insert(name, value); }
+    }
+    return Ok(());
+}
+
+fn resolve_exp<A: Clone>(e: &Exp<A>, constants: &Constants, bound: &BTreeSet<String>) -> Result<Exp<A>, String> {
+    match e {
+        Exp::Name(a, n) if !bound.contains(n) => match constants.get(n) {
+            Some(v) => Ok(Exp::Literal(a.clone(), v.clone())),
+            None => {
+                check_undeclared(n, constants)?;
+                Ok(e.clone())
+            }
+        },
+        Exp::Root(_) | Exp::Name(_, _) | Exp::Literal(_, _) => Ok(e.clone()),
+        Exp::Unary(a, op, e1) => Ok(Exp::Unary(a.clone(), *op, Box::new(resolve_exp(e1, constants, bound)?))),
+        Exp::Binary(a, op, e1, e2) => Ok(Exp::Binary(a.clone(), *op,
+            Box::new(resolve_exp(e1, constants, bound)?),
+            Box::new(resolve_exp(e2, constants, bound)?))),
+        Exp::Ternary(a, op, e1, e2, e3) => Ok(Exp::Ternary(a.clone(), *op,
+            Box::new(resolve_exp(e1, constants, bound)?),
+            Box::new(resolve_exp(e2, constants, bound)?),
+            Box::new(resolve_exp(e3, constants, bound)?))),
+        Exp::Call(a, f, args) => Ok(Exp::Call(a.clone(), *f,
+            args.iter().map(|arg| resolve_exp(arg, constants, bound)).collect::<Result<_, _>>()?)),
+        Exp::Choose(a, k, e1, cond) => {
+            let mut inner = bound.clone();
+            inner.insert(k.clone());
+            let cond = match cond {
+                Some(c) => Some(Box::new(resolve_exp(c, constants, &inner)?)),
+                None => None,
+            };
+            Ok(Exp::Choose(a.clone(), k.clone(), Box::new(resolve_exp(e1, constants, bound)?), cond))
+        }
+    }
+}
+
+fn resolve_lval<A: Clone>(lv: &LVal<A>, constants: &Constants, bound: &BTreeSet<String>) -> Result<LVal<A>, String> {
+    match lv {
+        LVal::Name(_, n) if !bound.contains(n) && constants.contains_key(n) => {
+            Err(format!("cannot assign to constant `{}`; write `.{} := ...` to assign to the memory key", n, n))
+        }
+        LVal::Name(_, n) if !bound.contains(n) && is_constant_name(n) => {
+            Err(format!("cannot assign to `{}`; names in capitals are reserved for constants, so write `.{} := ...` to assign to the memory key", n, n))
+        }
+        LVal::Name(_, n) if bound.contains(n) => {
+            Err(format!("cannot assign to `{}`, which is a parameter; write `.{} := ...` to assign to the memory key", n, n))
+        }
+        LVal::Root(_) | LVal::Name(_, _) => Ok(lv.clone()),
+        LVal::Index(a, x, i) => Ok(LVal::Index(a.clone(),
+            Box::new(resolve_lval(x, constants, bound)?),
+            Box::new(resolve_exp(i, constants, bound)?))),
+    }
+}
+
+fn resolve_block<A: Clone>(b: &Block<A>, constants: &Constants) -> Result<Block<A>, String> {
+    let mut bound = BTreeSet::new();
+    let mut parameters = Vec::new();
+    for (p, e) in &b.parameters {
+        parameters.push((p.clone(), resolve_exp(e, constants, &bound)?));
+        bound.insert(p.clone());
+    }
+
+    let mut attributes = Vec::new();
+    for attr in &b.attributes {
+        attributes.push(match attr {
+            Attribute::Every(a, period) => Attribute::Every(a.clone(), resolve_exp(period, constants, &bound)?),
+            Attribute::Priority(a, p) => Attribute::Priority(a.clone(), resolve_exp(p, constants, &bound)?),
+            Attribute::Weight(_, _) | Attribute::Disabled(_) => attr.clone(),
+        });
+    }
+
+    let mut guards = Vec::new();
+    for g in &b.guards {
+        guards.push(resolve_exp(g, constants, &bound)?);
+    }
+
+    let mut assignments = Vec::new();
+    for (lv, e) in &b.assignments {
+        assignments.push((resolve_lval(lv, constants, &bound)?, resolve_exp(e, constants, &bound)?));
+    }
+
+    return Ok(Block {
+        annotation: b.annotation.clone(),
+        attributes: attributes,
+        name: b.name.clone(),
+        parameters: parameters,
+        guards: guards,
+        assignments: assignments,
+    });
+}
+
+/// Replace the constants in an expression from outside the program (e.g.
+/// `ppppl read`) with their values.
+pub fn resolve_constants<A: Clone>(e: &Exp<A>, constants: &BTreeMap<String, Value>) -> Result<Exp<A>, String> {
+    return resolve_exp(e, constants, &BTreeSet::new());
+}
+
+/// Like `resolve_constants`, but for an assignment (e.g. `ppppl write`).
+pub fn resolve_constants_in_assignment<A: Clone>(lv: &LVal<A>, e: &Exp<A>, constants: &BTreeMap<String, Value>) -> Result<(LVal<A>, Exp<A>), String> {
+    let bound = BTreeSet::new();
+    return Ok((resolve_lval(lv, constants, &bound)?, resolve_exp(e, constants, &bound)?));
+}
+
+// A process compiles to a control-flow graph whose nodes become blocks.
+// Steps do the actual work.  Branches evaluate a `while` or `if` condition in
+// their own atomic step, so that the condition sees the effects of the step
+// before it.
+enum Node<A> {
+    Step(A, String, Vec<Exp<A>>, Vec<(LVal<A>, Exp<A>)>, String),
+    Branch(A, String, Exp<A>, String, String),
+}
+
+struct ProcessCompiler<A> {
+    name: String,
+    steps: BTreeSet<String>,
+    nodes: Vec<Node<A>>,
+    next_branch_id: usize,
+}
+
+impl<A: Clone> ProcessCompiler<A> {
+
+    fn collect_steps(&mut self, stmts: &[Stmt<A>]) -> Result<(), String> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Step(_, label, _, _) => {
+                    if !self.steps.insert(label.clone()) {
+                        return Err(format!("process `{}` has two steps named `{}`", self.name, label));
+                    }
+                }
+                Stmt::While(_, _, body) => { self.collect_steps(body)?; }
+                Stmt::If(_, _, t, e) => { self.collect_steps(t)?; self.collect_steps(e)?; }
+                Stmt::Goto(_, _) => { }
+            }
+        }
+        return Ok(());
+    }
+
+    fn fresh_label(&mut self, kind: &str) -> String {
+        self.next_branch_id += 1;
+        return format!("{}#{}", kind, self.next_branch_id);
+    }
+
+    /// Compile `stmts`, which continue to the node labeled `next` when they
+    /// finish.  Returns the label of the first node.
+    fn compile(&mut self, stmts: &[Stmt<A>], next: String) -> Result<String, String> {
+        let mut next = next;
+        for stmt in stmts.iter().rev() {
+            next = match stmt {
+                Stmt::Step(a, label, guards, assignments) => {
+                    self.nodes.push(Node::Step(a.clone(), label.clone(), guards.clone(), assignments.clone(), next));
+                    label.clone()
+                }
+                Stmt::While(a, cond, body) => {
+                    let label = self.fresh_label("while");
+                    let body_entry = self.compile(body, label.clone())?;
+                    self.nodes.push(Node::Branch(a.clone(), label.clone(), cond.clone(), body_entry, next));
+                    label
+                }
+                Stmt::If(a, cond, t, e) => {
+                    let label = self.fresh_label("if");
+                    let t_entry = self.compile(t, next.clone())?;
+                    let e_entry = self.compile(e, next)?;
+                    self.nodes.push(Node::Branch(a.clone(), label.clone(), cond.clone(), t_entry, e_entry));
+                    label
+                }
+                Stmt::Goto(_, label) => {
+                    if !self.steps.contains(label) {
+                        return Err(format!("process `{}` has no step `{}`", self.name, label));
+                    }
+                    label.clone()
+                }
+            };
+        }
+        return Ok(next);
+    }
+
+}
+
+fn blob_literal<A>(a: A, s: &str) -> Exp<A> {
+    Exp::Literal(a, Value::Blob(str2blob(s)))
+}
+
+/// `container[k1][k2]...`, or `default` if any of the keys is missing.
+fn lookup_or<A: Clone>(a: &A, container: Exp<A>, keys: &[Exp<A>], default: &Exp<A>) -> Exp<A> {
+    match keys.split_first() {
+        None => container,
+        Some((k, rest)) => Exp::Ternary(a.clone(), TernaryOp::IF,
+            Box::new(Exp::Binary(a.clone(), BinaryOp::IN, Box::new(k.clone()), Box::new(container.clone()))),
+            Box::new(lookup_or(a, Exp::Binary(a.clone(), BinaryOp::INDEX, Box::new(container), Box::new(k.clone())), rest, default)),
+            Box::new(default.clone())),
+    }
+}
+
+fn desugar_process<A: Clone>(p: &Process<A>, out: &mut Vec<Block<A>>) -> Result<(), String> {
+    let full_name = p.name.clone();
+    let mut compiler = ProcessCompiler {
+        name: full_name.clone(),
+        steps: BTreeSet::new(),
+        nodes: Vec::new(),
+        next_branch_id: 0,
+    };
+    compiler.collect_steps(&p.body)?;
+    let entry = compiler.compile(&p.body, DONE_LABEL.to_string())?;
+
+    // Each binding of the process parameters gets its own program counter at
+    // `.["@pc"][name][k1][k2]...`, which starts out at the entry point.
+    let a = &p.annotation;
+    let mut pc_keys = vec![blob_literal(a.clone(), PC_KEY), blob_literal(a.clone(), &full_name)];
+    for (param, _) in &p.parameters {
+        pc_keys.push(Exp::Name(a.clone(), param.clone()));
+    }
+    let pc = lookup_or(a, Exp::Root(a.clone()), &pc_keys, &blob_literal(a.clone(), &entry));
+    let pc_lval = pc_keys.iter().fold(LVal::Root(a.clone()), |lv, k| LVal::Index(a.clone(), Box::new(lv), Box::new(k.clone())));
+    let at = |label: &str| Exp::Binary(a.clone(), BinaryOp::EQ, Box::new(pc.clone()), Box::new(blob_literal(a.clone(), label)));
+
+    // NOTE: nodes come out of the compiler in reverse order.
+    for node in compiler.nodes.into_iter().rev() {
+        let (annotation, label, mut guards, mut assignments, pc_update) = match node {
+            Node::Step(annotation, label, guards, assignments, next) => {
+                let next = blob_literal(annotation.clone(), &next);
+                (annotation, label, guards, assignments, next)
+            }
+            Node::Branch(annotation, label, cond, t, e) => {
+                let next = Exp::Ternary(annotation.clone(), TernaryOp::IF,
+                    Box::new(cond),
+                    Box::new(blob_literal(annotation.clone(), &t)),
+                    Box::new(blob_literal(annotation.clone(), &e)));
+                (annotation, label, vec![], vec![], next)
+            }
+        };
+        guards.insert(0, at(&label));
+        assignments.push((pc_lval.clone(), pc_update));
+        out.push(Block {
+            annotation: annotation,
+            attributes: vec![],
+            name: format!("{}.{}", full_name, label),
+            parameters: p.parameters.clone(),
+            guards: guards,
+            assignments: assignments,
+        });
+    }
+
+    return Ok(());
+}
+
+fn collect_templates<'a, A>(items: &'a [Item<A>], out: &mut BTreeMap<String, &'a Template<A>>) -> Result<(), String> {
+    for item in items {
+        match item {
+            Item::Template(t) => {
+                if out.insert(t.name.clone(), t).is_some() {
+                    return Err(format!("duplicate template `{}`", t.name));
+                }
+            }
+            Item::Block(_) | Item::Instance(_) | Item::Process(_) | Item::Init(_, _) | Item::Schema(_, _) => { }
+            Item::Const(_, _, _) | Item::Enum(_, _, _) => { }
+        }
+    }
+    return Ok(());
+}
+
+// Top-level declarations, after desugaring.
+struct Declarations<A> {
+    blocks: Vec<Block<A>>,
+    init: Vec<(LVal<A>, Exp<A>)>,
+    schema: BTreeMap<String, Type>,
+    constants: Constants,
+}
+
+fn expand_items<A: Clone>(items: &[Item<A>], templates: &BTreeMap<String, &Template<A>>, out: &mut Declarations<A>) -> Result<(), String> {
+    for item in items {
+        match item {
+            Item::Block(b) => { out.blocks.push(b.clone()); }
+            Item::Template(_) => { }
+            Item::Instance(instance) => {
+                let t = match templates.get(&instance.template) {
+                    Some(t) => t,
+                    None => { return Err(format!("unknown template `{}`", instance.template)); }
+                };
+                if t.parameters.len() != instance.arguments.len() {
+                    return Err(format!("template `{}` takes {} arguments, but instance `{}` gives {}",
+                        t.name, t.parameters.len(), instance.name, instance.arguments.len()));
+                }
+                let args: Substitution<A> = t.parameters.iter().cloned().zip(instance.arguments.iter().cloned()).collect();
+                for b in &t.blocks {
+                    out.blocks.push(subst_block(b, format!("{}.{}", instance.name, b.name), &args)?);
+                }
+            }
+            Item::Process(p) => { desugar_process(p, &mut out.blocks)?; }
+            Item::Init(_, assignments) => { out.init.extend(assignments.iter().cloned()); }
+            Item::Schema(_, decls) => {
+                // NOTE: imports don't rename memory keys, so the schema is
+                // shared by the whole program.
+                for (key, t) in decls {
+                    match out.schema.get(key) {
+                        Some(other) if other != t => {
+                            return Err(format!("key `{}` is declared as both {} and {}", key, other, t));
+                        }
+                        _ => { out.schema.insert(key.clone(), t.clone()); }
+                    }
+                }
+            }
+            Item::Const(_, name, e) => {
+                // NOTE: like the schema, constants are shared by the whole
+                // program rather than renamed by imports.
+                if !is_constant_name(name) {
+                    return Err(format!("constant names must be written in capitals, like `{}`", name.to_uppercase()));
+                }
+                let value = constant_value(e, &out.constants)?;
+                declare_constant(&mut out.constants, name.clone(), value)?;
+            }
+            Item::Enum(_, name, variants) => {
+                let mut seen = BTreeSet::new();
+                for v in variants {
+                    if !seen.insert(v) {
+                        return Err(format!("enum `{}` has two variants named `{}`", name, v));
+                    }
+                    declare_constant(&mut out.constants, format!("{}.{}", name, v), Value::Blob(str2blob(v)))?;
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+pub fn module<A: Clone>(annotation: A, imports: Vec<Import<A>>, items: Vec<Item<A>>) -> Result<Module<A>, String> {
+    let mut templates = BTreeMap::new();
+    collect_templates(&items, &mut templates)?;
+
+    let mut decls = Declarations {
+        blocks: Vec::new(),
+        init: Vec::new(),
+        schema: BTreeMap::new(),
+        constants: BTreeMap::new(),
+    };
+    expand_items(&items, &templates, &mut decls)?;
+
+    // Constants are substituted last, so that they can appear in template
+    // arguments and process bodies.
+    let mut blocks = Vec::new();
+    for b in &decls.blocks {
+        blocks.push(resolve_block(b, &decls.constants).map_err(|e| format!("in block `{}`: {}", b.name, e))?);
+    }
+    let mut init = Vec::new();
+    for (lv, e) in &decls.init {
+        init.push(resolve_constants_in_assignment(lv, e, &decls.constants).map_err(|e| format!("in init: {}", e))?);
+    }
+
+    let mut names = BTreeSet::new();
+    for b in &blocks {
+        if !names.insert(&b.name) {
+            return Err(format!("duplicate block name `{}`", b.name));
+        }
+    }
+
+    return Ok(Module {
+        annotation: annotation,
+        imports: imports,
+        blocks: blocks,
+        init: init,
+        schema: decls.schema,
+        constants: decls.constants,
+    });
+}
+
+/// An item or statement as parsed, or an assignment, which belongs to the
+/// block or step before it.
+pub enum Part<T> {
+    Whole(T),
+    Assign(usize, LVal<SourceSpan>, Exp<SourceSpan>),
+}
+
+/// Things that assignments can belong to: blocks and steps.
+pub trait HasAssignments {
+    fn assignments(&mut self) -> Option<(&mut SourceSpan, &mut Vec<(LVal<SourceSpan>, Exp<SourceSpan>)>)>;
+}
+
+impl HasAssignments for Block<SourceSpan> {
+    fn assignments(&mut self) -> Option<(&mut SourceSpan, &mut Vec<(LVal<SourceSpan>, Exp<SourceSpan>)>)> {
+        return Some((&mut self.annotation, &mut self.assignments));
+    }
+}
+
+impl HasAssignments for Item<SourceSpan> {
+    fn assignments(&mut self) -> Option<(&mut SourceSpan, &mut Vec<(LVal<SourceSpan>, Exp<SourceSpan>)>)> {
+        match self {
+            Item::Block(b) => b.assignments(),
+            _ => None,
+        }
+    }
+}
+
+impl HasAssignments for Stmt<SourceSpan> {
+    fn assignments(&mut self) -> Option<(&mut SourceSpan, &mut Vec<(LVal<SourceSpan>, Exp<SourceSpan>)>)> {
+        match self {
+            Stmt::Step(span, _, _, assignments) => Some((span, assignments)),
+            _ => None,
+        }
+    }
+}
+
+/// Give each block or step the assignments that were parsed after it.
+pub fn attach_assignments<T: HasAssignments>(parts: Vec<Part<T>>) -> Result<Vec<T>, String> {
+    let mut res: Vec<T> = Vec::new();
+    for part in parts {
+        match part {
+            Part::Whole(t) => { res.push(t); }
+            Part::Assign(end, lv, e) => match res.last_mut().and_then(|t| t.assignments()) {
+                Some((span, assignments)) => {
+                    span.end = end;
+                    assignments.push((lv, e));
+                }
+                None => { return Err("an assignment must come after a block's (or a step's) guards".to_string()); }
+            },
+        }
+    }
+    return Ok(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{apply_init, sim_step, StepOutcome};
+    use crate::parse::ModuleParser;
+    use crate::scheduler;
+    use crate::storage::{Backend, MemoryBackend, Transaction};
+    use num_bigint::BigInt;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn block_names(source: &str) -> Vec<String> {
+        let module = ModuleParser::new().parse(source).unwrap();
+        return module.blocks.iter().map(|b| b.name.clone()).collect();
+    }
+
+    // Load `source` into an empty pmemory and run it until nothing can run.
+    // Returns the blocks that ran, in order.
+    fn run(s: &mut MemoryBackend, source: &str) -> Vec<String> {
+        let module = ModuleParser::new().parse(source).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut tx = s.start_transaction().unwrap();
+        tx.replace_code(source).unwrap();
+        apply_init(&module, &mut tx, &mut rng).unwrap();
+        tx.commit().unwrap();
+
+        let mut scheduler = scheduler::by_name("first-eligible").unwrap();
+        let mut ran = Vec::new();
+        loop {
+            match sim_step(s, &mut *scheduler, &mut rng).unwrap() {
+                StepOutcome::TriggeredBlock(name, _) => { ran.push(name); }
+                StepOutcome::Deadlock => { return ran; }
+                StepOutcome::WaitingUntil(_) => panic!("nothing waits on a timer"),
+            }
+        }
+    }
+
+    fn read(s: &mut MemoryBackend, keys: &[&str]) -> Option<Value> {
+        let path: Vec<Value> = keys.iter().map(|k| Value::Blob(str2blob(k))).collect();
+        return s.start_transaction().unwrap().read_memory(&path).unwrap();
+    }
+
+    fn int(i: i64) -> Value {
+        Value::Int(BigInt::from(i))
+    }
+
+    #[test]
+    fn template_parameters_dont_capture_names_in_arguments() {
+        // `k` in the argument is the memory key, not the template's `k`.
+        let source = "
+template copy(src) {
+    def go(k in src):
+        require !(k in out);
+        out[k] := src[k];
+}
+
+instance c = copy(m[k]);
+
+init {
+    k := \"inner\";
+    m := {};
+    m[\"inner\"] := {};
+    m[\"inner\"][\"x\"] := 1;
+    m[\"inner\"][\"y\"] := 2;
+    out := {};
+}
+";
+        let module = ModuleParser::new().parse(source).unwrap();
+        assert_eq!(module.blocks[0].name, "c.go");
+        assert_eq!(module.blocks[0].parameters[0].0, "k'");
+
+        let mut s = MemoryBackend::new();
+        assert_eq!(run(&mut s, source), vec!["c.go", "c.go"]);
+        assert_eq!(read(&mut s, &["out", "x"]), Some(int(1)));
+        assert_eq!(read(&mut s, &["out", "y"]), Some(int(2)));
+        assert_eq!(read(&mut s, &["out", "inner"]), None);
+    }
+
+    const LOOP: &str = "
+process p {
+    step a:
+        x := 1;
+    while x < 3 {
+        step b:
+            x := x + 1;
+    }
+    if x == 3 {
+        step c:
+            y := true;
+    }
+}
+";
+
+    #[test]
+    fn a_process_becomes_a_block_per_step_and_test() {
+        let mut names = block_names(LOOP);
+        names.sort();
+        assert_eq!(names, vec!["p.a", "p.b", "p.c", "p.if#1", "p.while#2"]);
+    }
+
+    #[test]
+    fn a_process_runs_its_steps_in_order_and_stops_at_done() {
+        let mut s = MemoryBackend::new();
+        assert_eq!(run(&mut s, LOOP), vec![
+            "p.a",
+            "p.while#2", "p.b",
+            "p.while#2", "p.b",
+            "p.while#2",
+            "p.if#1", "p.c",
+        ]);
+        assert_eq!(read(&mut s, &["x"]), Some(int(3)));
+        assert_eq!(read(&mut s, &["y"]), Some(Value::Bool(true)));
+        assert_eq!(read(&mut s, &["@pc", "p"]), Some(Value::Blob(str2blob("@done"))));
+    }
+
+    #[test]
+    fn goto_moves_the_program_counter_back() {
+        let source = "
+process p(j in jobs) {
+    step work:
+        require jobs[j] > 0;
+        jobs[j] := jobs[j] - 1;
+    goto work;
+}
+
+init {
+    jobs := {};
+    jobs[\"a\"] := 2;
+}
+";
+        let mut s = MemoryBackend::new();
+        assert_eq!(run(&mut s, source), vec!["p.work", "p.work"]);
+        assert_eq!(read(&mut s, &["jobs", "a"]), Some(int(0)));
+        // NOTE: each binding of the parameters has its own program counter.
+        assert_eq!(read(&mut s, &["@pc", "p", "a"]), Some(Value::Blob(str2blob("work"))));
+    }
+
+    #[test]
+    fn undefined_constants_are_rejected() {
+        let parse = |source: &str| ModuleParser::new().parse(source).map(|_| ()).map_err(|e| format!("{:?}", e));
+        assert_eq!(parse("const MAX = 3;\n\ndef f:\n    require x < MAX;\n    x := x + 1;\n"), Ok(()));
+        assert!(parse("const MAX = 3;\n\ndef f:\n    require x < MAXX;\n    x := x + 1;\n").unwrap_err().contains("undefined constant `MAXX`"));
+        assert!(parse("def f:\n    MAX := 1;\n").unwrap_err().contains("names in capitals are reserved for constants"));
+        // NOTE: `.MAXX` is the memory key.
+        assert_eq!(parse("def f:\n    require x < .MAXX;\n    .MAXX := 1;\n"), Ok(()));
+    }
+
+    #[test]
+    fn keywords_can_still_be_names() {
+        let source = "def template:\n    schema := choose k in choose;\n    import[as] := const + enum;\n\nprocess instance {\n    step go:\n        .[\"where\"] := 1;\n}\n";
+        assert_eq!(block_names(source), vec!["template", "instance.go"]);
+    }
+
+}
@@ -1,6 +1,8 @@
 use crate::syntax::*;
-use crate::storage::{Storage,Transaction,StorageError};
-use rand::Rng;
+use crate::storage::{Backend,Transaction,StorageError,Cause};
+use crate::scheduler::Scheduler;
+use rand::{Rng, RngCore};
+use num_bigint::{BigInt, RandBigInt};
 use std::fmt::Debug;
 use std::collections::BTreeMap;
 
@@ -11,7 +13,13 @@ pub enum ExecutionError {
     CannotEvalUnary(UnaryOp, Value),
     CannotEvalBinary(BinaryOp, Value, Value),
     CannotEvalIfOnNonBooleanCond(Value),
-    CannotWriteToBoundParameter(String),
+    CannotEvalBuiltin(Builtin, Vec<Value>),
+    CannotChooseFrom(Value),
+    NothingToChoose(Value),
+    CannotEvalPriority(Value),
+    /// `x := ...` where `x` is a block parameter.  Parameters are read-only;
+    /// the memory key of the same name is written `.x`.
+    CannotWriteToBoundName { name: String, hint: String },
     MissingKey(Value, Value),
     StorageFault(StorageError),
     StorageRootSomehowVanished,
@@ -23,7 +31,33 @@ impl From<StorageError> for ExecutionError {
     }
 }
 
-type BoundNames = BTreeMap<String, Value>;
+pub type BoundNames = BTreeMap<String, Value>;
+
+/// Hidden key in pmemory under which `@every` blocks record the last time
+/// they ran.  It is not a valid PPPL name, so it can't collide with a program
+/// variable.
+const TIMERS_KEY: &str = "@every";
+
+/// Whether `k` is the top-level key that holds the timers, which are the
+/// runtime's own bookkeeping rather than the program's data.  `ppppl read .`
+/// doesn't show them, and writing `.` keeps them.
+pub fn is_hidden_key(k: &Value) -> bool {
+    match k {
+        Value::Blob(b) => TIMERS_KEY.as_bytes() == &b[..],
+        _ => false,
+    }
+}
+
+/// `root` without the timers, for showing to people.
+pub fn without_hidden_keys(root: Value) -> Value {
+    match root {
+        Value::Dict(mut mapping) => {
+            mapping.remove(&Value::Blob(str2blob(TIMERS_KEY)));
+            Value::Dict(mapping)
+        }
+        v => v,
+    }
+}
 
 fn eval_unary(op: UnaryOp, v: &Value) -> Result<Value, ExecutionError> {
     match (op, v) {
@@ -58,32 +92,110 @@ fn eval_binary(op: BinaryOp, v1: &Value, v2: &Value) -> Result<Value, ExecutionE
     }
 }
 
-fn eval_ternary<A:Copy + Debug>(op: TernaryOp, e1: &Exp<A>, e2: &Exp<A>, e3: &Exp<A>, env: &Transaction, names: &BoundNames) -> Result<Value, ExecutionError> {
-    match (op, eval(e1, env, names)?) {
-        (TernaryOp::IF, Value::Bool(b)) => if b { eval(e2, env, names) } else { eval(e3, env, names) },
+fn eval_ternary<A:Copy + Debug>(op: TernaryOp, e1: &Exp<A>, e2: &Exp<A>, e3: &Exp<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Value, ExecutionError> {
+    match (op, eval(e1, env, names, rng)?) {
+        (TernaryOp::IF, Value::Bool(b)) => if b { eval(e2, env, names, rng) } else { eval(e3, env, names, rng) },
         (TernaryOp::IF, v1) => Err(ExecutionError::CannotEvalIfOnNonBooleanCond(v1)),
     }
 }
 
-fn _eval<A:Copy + Debug>(e: &Exp<A>, env: &Transaction, names: &BoundNames) -> Result<Value, ExecutionError> {
+fn eval_builtin(f: Builtin, args: Vec<Value>, env: &dyn Transaction, rng: &mut dyn RngCore) -> Result<Value, ExecutionError> {
+    match (f, &args[..]) {
+        (Builtin::NOW, []) => Ok(Value::Int(env.now().clone())),
+        // NOTE: both bounds are inclusive, so `random(1, 6)` rolls a die.
+        (Builtin::RANDOM, [Value::Int(lo), Value::Int(hi)]) if lo <= hi =>
+            Ok(Value::Int(rng.gen_bigint_range(lo, &(hi + BigInt::from(1))))),
+        _ => Err(ExecutionError::CannotEvalBuiltin(f, args)),
+    }
+}
+
+fn eval_choose<A:Copy + Debug>(k: &str, e1: &Exp<A>, cond: &Option<Box<Exp<A>>>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Value, ExecutionError> {
+    let mapping = match eval(e1, env, names, rng)? {
+        Value::Dict(mapping) => mapping,
+        v => { return Err(ExecutionError::CannotChooseFrom(v)); }
+    };
+
+    let mut candidates = Vec::new();
+    let mut inner_names = names.clone();
+    for key in mapping.keys() {
+        match cond {
+            Some(c) => {
+                inner_names.insert(k.to_string(), key.clone());
+                if let Ok(Value::Bool(true)) = eval(c, env, &inner_names, rng) {
+                    candidates.push(key);
+                }
+            }
+            None => { candidates.push(key); }
+        }
+    }
+
+    if candidates.is_empty() {
+        return Err(ExecutionError::NothingToChoose(Value::Dict(mapping.clone())));
+    }
+    let i: usize = rng.gen_range(0..candidates.len());
+    return Ok(candidates[i].clone());
+}
+
+fn _eval<A:Copy + Debug>(e: &Exp<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Value, ExecutionError> {
     match e {
         Exp::Root(_) => match env.read_memory(&Vec::new())? {
-            Some(root) => Ok(root.clone()),
+            Some(root) => Ok(root),
             None => Err(ExecutionError::StorageRootSomehowVanished),
         },
         Exp::Name(loc, n) => match names.get(n) {
             Some(v) => Ok(v.clone()),
-            _ => eval::<A>(&Exp::Binary(*loc, BinaryOp::INDEX, Box::new(Exp::Root(*loc)), Box::new(Exp::Literal(*loc, Value::Blob(str2blob(&n))))), env, names),
+            _ => eval::<A>(&Exp::Binary(*loc, BinaryOp::INDEX, Box::new(Exp::Root(*loc)), Box::new(Exp::Literal(*loc, Value::Blob(str2blob(&n))))), env, names, rng),
         },
         Exp::Literal(_, v) => Ok(v.clone()),
-        Exp::Unary(_, op, e1) => eval_unary(*op, &eval::<A>(e1, env, names)?),
-        Exp::Binary(_, op, e1, e2) => eval_binary(*op, &eval::<A>(e1, env, names)?, &eval::<A>(e2, env, names)?),
-        Exp::Ternary(_, op, e1, e2, e3) => eval_ternary(*op, e1, e2, e3, env, names),
+        Exp::Unary(_, op, e1) => eval_unary(*op, &eval::<A>(e1, env, names, rng)?),
+        Exp::Binary(_, BinaryOp::INDEX, e1, e2) => match memory_path(e, env, names, rng)? {
+            Some(mut path) => match env.read_memory(&path)? {
+                Some(v) => Ok(v),
+                None => {
+                    // NOTE: the error is about the innermost container that
+                    // exists, found from the path rather than by evaluating
+                    // `e1` again (which could draw from `rng` again).
+                    let mut key = path.pop().unwrap();
+                    loop {
+                        match env.read_memory(&path)? {
+                            Some(container) => { return eval_binary(BinaryOp::INDEX, &container, &key); }
+                            None => { key = path.pop().unwrap(); }
+                        }
+                    }
+                }
+            },
+            None => eval_binary(BinaryOp::INDEX, &eval::<A>(e1, env, names, rng)?, &eval::<A>(e2, env, names, rng)?),
+        },
+        Exp::Binary(_, op, e1, e2) => eval_binary(*op, &eval::<A>(e1, env, names, rng)?, &eval::<A>(e2, env, names, rng)?),
+        Exp::Ternary(_, op, e1, e2, e3) => eval_ternary(*op, e1, e2, e3, env, names, rng),
+        Exp::Call(_, f, args) => {
+            let mut vals = Vec::new();
+            for arg in args {
+                vals.push(eval::<A>(arg, env, names, rng)?);
+            }
+            eval_builtin(*f, vals, env, rng)
+        }
+        Exp::Choose(_, k, e1, cond) => eval_choose(k, e1, cond, env, names, rng),
     }
 }
 
-pub fn eval<A:Copy + Debug>(e: &Exp<A>, env: &Transaction, names: &BoundNames) -> Result<Value, ExecutionError> {
-    let res = _eval(e, env, names);
+/// If `e` is a location in pmemory (`.`, a memory key `x`, or an index into
+/// one of those), the path to it.  Reading the path alone, rather than
+/// everything above it, keeps an optimistic transaction's read set small.
+fn memory_path<A:Copy + Debug>(e: &Exp<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Option<Vec<Value>>, ExecutionError> {
+    match e {
+        Exp::Root(_) => Ok(Some(Vec::new())),
+        Exp::Name(_, n) if !names.contains_key(n) => Ok(Some(vec![Value::Blob(str2blob(n))])),
+        Exp::Binary(_, BinaryOp::INDEX, e1, e2) => match memory_path(e1, env, names, rng)? {
+            Some(path) => Ok(Some(append_in_place(path, eval(e2, env, names, rng)?))),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+pub fn eval<A:Copy + Debug>(e: &Exp<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Value, ExecutionError> {
+    let res = _eval(e, env, names, rng);
     // match &res {
     //     Ok(val) => { println!("{:?} ==> {:?}", e, val); },
     //     Err(err) => { println!("{:?} ==> {:?}", e, err); },
@@ -91,16 +203,16 @@ pub fn eval<A:Copy + Debug>(e: &Exp<A>, env: &Transaction, names: &BoundNames) -
     return res;
 }
 
-fn instantiate_params<A:Copy + Debug, F>(params: &Vec<(String, Exp<A>)>, index: usize, env: &Transaction, out: &mut BoundNames, callback: &mut F) where F: FnMut(&BoundNames) -> () {
+fn instantiate_params<A:Copy + Debug, F>(params: &Vec<(String, Exp<A>)>, index: usize, env: &dyn Transaction, out: &mut BoundNames, rng: &mut dyn RngCore, callback: &mut F) where F: FnMut(&BoundNames, &mut dyn RngCore) -> () {
     if index >= params.len() {
-        callback(out);
+        callback(out, rng);
     } else {
         let (name, exp) = &params[index];
-        match eval(exp, env, out) {
+        match eval(exp, env, out, rng) {
             Ok(Value::Dict(m)) => {
                 for k in m.keys() {
                     out.insert(name.clone(), k.clone());
-                    instantiate_params(params, index + 1, env, out, callback);
+                    instantiate_params(params, index + 1, env, out, rng, callback);
                     out.remove(name);
                 }
             }
@@ -109,25 +221,120 @@ fn instantiate_params<A:Copy + Debug, F>(params: &Vec<(String, Exp<A>)>, index:
     }
 }
 
-fn find_eligible_blocks<A:Copy + Debug>(m: &Module<A>, env: &Transaction) -> Vec<(usize, BoundNames)> {
+fn timer_path(block_name: &str) -> Vec<Value> {
+    vec![Value::Blob(str2blob(TIMERS_KEY)), Value::Blob(str2blob(block_name))]
+}
+
+fn is_now<A>(e: &Exp<A>) -> bool {
+    match e {
+        Exp::Call(_, Builtin::NOW, _) => true,
+        _ => false,
+    }
+}
+
+/// If `cond` compares `now()` against some other expression (e.g.
+/// `now() >= deadline`), the earliest time at which it could become true.
+fn time_guard_deadline<A:Copy + Debug>(cond: &Exp<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Option<BigInt> {
+    let (deadline, strict) = match cond {
+        Exp::Binary(_, BinaryOp::GE, now, e) | Exp::Binary(_, BinaryOp::LE, e, now) if is_now(now) => (e, false),
+        Exp::Binary(_, BinaryOp::GT, now, e) | Exp::Binary(_, BinaryOp::LT, e, now) if is_now(now) => (e, true),
+        _ => { return None; }
+    };
+    match eval(deadline, env, names, rng) {
+        Ok(Value::Int(t)) => Some(if strict { t + 1 } else { t }),
+        _ => None,
+    }
+}
+
+/// The earliest time at which all of the block's `@every` timers will have
+/// expired.  Blocks that have never run are due immediately.
+fn timer_deadline<A:Copy + Debug>(b: &Block<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<BigInt, ExecutionError> {
+    let mut deadline = BigInt::from(0);
+    for attr in &b.attributes {
+        match attr {
+            Attribute::Every(_, period) => {
+                let period = eval(period, env, names, rng)?;
+                match (env.read_memory(&timer_path(&b.name))?, period) {
+                    (Some(Value::Int(last)), Value::Int(period)) => { deadline = deadline.max(last + period); }
+                    (_, Value::Int(_)) => { }
+                    (_, period) => { return Err(ExecutionError::CannotEvalBinary(BinaryOp::PLUS, Value::Int(env.now().clone()), period)); }
+                }
+            }
+            Attribute::Priority(_, _) | Attribute::Weight(_, _) | Attribute::Disabled(_) => { }
+        }
+    }
+    return Ok(deadline);
+}
+
+fn is_disabled<A>(b: &Block<A>) -> bool {
+    b.attributes.iter().any(|attr| match attr { Attribute::Disabled(_) => true, _ => false })
+}
+
+fn priority<A:Copy + Debug>(b: &Block<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<BigInt, ExecutionError> {
+    let mut res = BigInt::from(0);
+    for attr in &b.attributes {
+        if let Attribute::Priority(_, e) = attr {
+            match eval(e, env, names, rng)? {
+                Value::Int(p) => { res = p; }
+                v => { return Err(ExecutionError::CannotEvalPriority(v)); }
+            }
+        }
+    }
+    return Ok(res);
+}
+
+/// Returns the eligible (block, binding, priority) triples and, when some
+/// instances are only held back by time, the earliest time at which one of
+/// them will be ready.  Fails if an eligible instance's priority can't be
+/// computed.
+fn find_eligible_blocks<A:Copy + Debug>(m: &Module<A>, env: &dyn Transaction, rng: &mut dyn RngCore) -> Result<(Vec<(usize, BoundNames, BigInt)>, Option<BigInt>), ExecutionError> {
     let blocks = &m.blocks;
+    let now = env.now();
     let mut res = Vec::new();
+    let mut wake_at: Option<BigInt> = None;
+    let mut error = None;
     for i in 0 .. blocks.len() {
         let block = &blocks[i];
-        instantiate_params(&block.parameters, 0, env, &mut BoundNames::new(), &mut |names| {
+        if is_disabled(block) {
+            continue;
+        }
+        instantiate_params(&block.parameters, 0, env, &mut BoundNames::new(), rng, &mut |names, rng| {
             let mut eligible = true;
+            let mut waiting_only_for_time = true;
+            let mut ready_at = now.clone();
+            match timer_deadline(block, env, names, rng) {
+                Ok(t) => if t > *now { eligible = false; ready_at = t; },
+                Err(_) => { eligible = false; waiting_only_for_time = false; }
+            }
             for cond in &blocks[i].guards {
-                match eval(&cond, env, names) {
+                match eval(&cond, env, names, rng) {
                     Ok(Value::Bool(true)) => { }
-                    _ => { eligible = false; }
+                    _ => {
+                        eligible = false;
+                        match time_guard_deadline(cond, env, names, rng) {
+                            Some(t) if t > *now => { ready_at = ready_at.max(t); }
+                            _ => { waiting_only_for_time = false; }
+                        }
+                    }
                 }
             }
             if eligible {
-                res.push((i, names.clone()));
+                match priority(block, env, names, rng) {
+                    Ok(p) => { res.push((i, names.clone(), p)); }
+                    Err(e) => if error.is_none() { error = Some(e); },
+                }
+            } else if waiting_only_for_time {
+                wake_at = Some(match wake_at.take() {
+                    Some(t) => t.min(ready_at),
+                    None => ready_at,
+                });
             }
         });
     }
-    return res;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    return Ok((res, wake_at));
 }
 
 fn append_in_place<T>(mut v: Vec<T>, x: T) -> Vec<T> {
@@ -135,53 +342,196 @@ fn append_in_place<T>(mut v: Vec<T>, x: T) -> Vec<T> {
     return v;
 }
 
-pub fn eval_lval<A:Copy + Debug>(lv: &LVal<A>, env: &Transaction, names: &BoundNames) -> Result<Vec<Value>, ExecutionError> {
+pub fn eval_lval<A:Copy + Debug>(lv: &LVal<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<Vec<Value>, ExecutionError> {
     match lv {
         LVal::Root(_) => Ok(Vec::new()),
         LVal::Name(a, x) =>
-            if names.contains_key(x) { Err(ExecutionError::CannotWriteToBoundParameter(x.clone())) }
-            else { eval_lval::<A>(&LVal::Index(a.clone(), Box::new(LVal::Root(a.clone())), Box::new(Exp::Literal(a.clone(), Value::Blob(str2blob(&x))))), env, names) }
-        LVal::Index(_, x, i) => Ok(append_in_place(eval_lval(&x, env, names)?, eval(i, env, names)?)),
+            if names.contains_key(x) { Err(ExecutionError::CannotWriteToBoundName { name: x.clone(), hint: format!("write `.{}` to assign to the memory key", x) }) }
+            else { eval_lval::<A>(&LVal::Index(a.clone(), Box::new(LVal::Root(a.clone())), Box::new(Exp::Literal(a.clone(), Value::Blob(str2blob(&x))))), env, names, rng) }
+        LVal::Index(_, x, i) => Ok(append_in_place(eval_lval(&x, env, names, rng)?, eval(i, env, names, rng)?)),
     }
 }
 
-pub fn do_assignment(path: &Vec<Value>, new_val: &Value, env: &mut Transaction) -> Result<(), ExecutionError> {
+pub fn do_assignment(path: &Vec<Value>, new_val: &Value, env: &mut dyn Transaction) -> Result<(), ExecutionError> {
+    if let (true, Value::Dict(mapping)) = (path.is_empty(), new_val) {
+        // NOTE: replacing all of memory (e.g. `load --reset`) doesn't reset
+        // timers, which the new value can't mention.
+        let mut mapping = mapping.clone();
+        let key = Value::Blob(str2blob(TIMERS_KEY));
+        match env.read_memory(&vec![key.clone()])? {
+            Some(v) => { mapping.insert(key, v); }
+            None => { mapping.remove(&key); }
+        }
+        env.write_memory(path, &Value::Dict(mapping))?;
+        return Ok(());
+    }
     env.write_memory(path, new_val)?;
     return Ok(());
 }
 
-fn exec_block<A:Copy + Debug>(b: &Block<A>, env: &mut Transaction, names: &BoundNames) -> Result<(), ExecutionError> {
+/// Apply a module's `init` section.  Each assignment is performed, in order,
+/// only if its target doesn't exist yet.
+pub fn apply_init<A:Copy + Debug>(m: &Module<A>, env: &mut dyn Transaction, rng: &mut dyn RngCore) -> Result<(), ExecutionError> {
+    let no_bound_names = BoundNames::new();
+    for (lval, exp) in &m.init {
+        let path = eval_lval(lval, env, &no_bound_names, rng)?;
+        if !env.exists(&path)? {
+            let val = eval(exp, env, &no_bound_names, rng)?;
+            do_assignment(&path, &val, env)?;
+        }
+    }
+    Ok(())
+}
+
+fn exec_block<A:Copy + Debug>(b: &Block<A>, env: &mut dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) -> Result<(), ExecutionError> {
     let mut prepped_assignments = Vec::new();
     for (lval, exp) in &b.assignments {
         prepped_assignments.push((
-            eval_lval(&lval, env, names)?,
-            eval(&exp, env, names)?));
+            eval_lval(&lval, env, names, rng)?,
+            eval(&exp, env, names, rng)?));
     }
     // TODO: check for aliasing
     for (chain, val) in prepped_assignments {
         do_assignment(&chain, &val, env)?;
     }
+    if b.attributes.iter().any(|attr| match attr { Attribute::Every(_, _) => true, _ => false }) {
+        let now = Value::Int(env.now().clone());
+        do_assignment(&timer_path(&b.name), &now, env)?;
+    }
     Ok(())
 }
 
 pub enum StepOutcome {
     TriggeredBlock(String, BoundNames),
+    /// Nothing can run until the given time (in milliseconds since the Unix
+    /// epoch), unless pmemory changes first.
+    WaitingUntil(BigInt),
     Deadlock,
 }
 
-pub fn sim_step(store: &mut Storage, rng: &mut rand::rngs::ThreadRng) -> Result<StepOutcome, ExecutionError> {
-    let mut tx = store.start_transaction()?;
-    let code = tx.read_code()?;
+/// A description of how `sim_step` picks blocks to run in the given module.
+pub fn describe_policy<A>(m: &Module<A>, scheduler: &dyn Scheduler) -> String {
+    let mut res = format!("{}, among the eligible blocks with the highest @priority", scheduler.describe());
+    let disabled: Vec<&str> = m.blocks.iter().filter(|b| is_disabled(b)).map(|b| b.name.as_str()).collect();
+    if !disabled.is_empty() {
+        res.push_str(&format!(" (disabled: {})", disabled.join(", ")));
+    }
+    return res;
+}
+
+/// Evaluate everything that made the instance `names` of block `b` eligible
+/// (parameter domains, timers, guards and priority) for the reads alone.
+fn reread_eligibility<A:Copy + Debug>(b: &Block<A>, env: &dyn Transaction, names: &BoundNames, rng: &mut dyn RngCore) {
+    let mut outer = BoundNames::new();
+    for (p, e) in &b.parameters {
+        // NOTE: the instance only depends on its own key being in the domain,
+        // so instances for different keys of `m` don't conflict.
+        match (names.get(p), memory_path(e, env, &outer, rng)) {
+            (Some(v), Ok(Some(path))) => { let _ = env.exists(&append_in_place(path, v.clone())); }
+            _ => { let _ = eval(e, env, &outer, rng); }
+        }
+        if let Some(v) = names.get(p) {
+            outer.insert(p.clone(), v.clone());
+        }
+    }
+    let _ = timer_deadline(b, env, names, rng);
+    for cond in &b.guards {
+        let _ = eval(cond, env, names, rng);
+    }
+    let _ = priority(b, env, names, rng);
+}
+
+/// Run one eligible block instance, if there is one.
+///
+/// Blocks are evaluated on a snapshot of pmemory without holding the write
+/// lock, so several runners can evaluate blocks at the same time; only
+/// committing is serialized.  If another runner commits a write to something
+/// the chosen instance read first, the step starts over.
+pub fn sim_step<B: Backend>(store: &mut B, scheduler: &mut dyn Scheduler, rng: &mut dyn RngCore) -> Result<StepOutcome, ExecutionError> {
+    loop {
+        let mut tx = store.start_optimistic()?;
+        let code = tx.read_code()?;
+
+        let (eligible_blocks, wake_at) = find_eligible_blocks(&code, &tx, rng)?;
+        if eligible_blocks.len() == 0 {
+            return Ok(match wake_at {
+                Some(t) => StepOutcome::WaitingUntil(t),
+                None => StepOutcome::Deadlock,
+            });
+        }
+        // println!("eligible blocks ~~> {}", blocks.len());
+        let best = eligible_blocks.iter().map(|(_, _, p)| p).max().cloned();
+        let mut candidates: Vec<(usize, BoundNames)> = eligible_blocks.into_iter()
+            .filter(|(_, _, p)| Some(p) == best.as_ref())
+            .map(|(i, names, _)| (i, names))
+            .collect();
+        let i = scheduler.pick(&code, &candidates, rng);
+        let (block_index, names) = candidates.swap_remove(i);
+        let block = &code.blocks[block_index];
+
+        // NOTE: only what the chosen instance depends on is checked at
+        // commit.  So the choice itself can be slightly stale: an instance
+        // with a higher @priority that became eligible in the meantime
+        // doesn't cause a conflict.
+        tx.forget_reads();
+        tx.set_cause(Cause::Block(block.name.clone(), names.clone()));
+        reread_eligibility(block, &tx, &names, rng);
+        exec_block(block, &mut tx, &names, rng)?;
+        match tx.commit() {
+            Err(StorageError::Conflict) => { continue; }
+            res => { res?; }
+        }
+        return Ok(StepOutcome::TriggeredBlock(block.name.clone(), names));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ModuleParser;
+    use crate::storage::MemoryBackend;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn instances_for_different_keys_dont_conflict() {
+        let source = "
+init {
+    m := {};
+    m[\"a\"] := 0;
+    m[\"b\"] := 0;
+}
+
+def bump(k in m):
+    require m[k] < 1;
+    m[k] := m[k] + 1;
+";
+        let module = ModuleParser::new().parse(source).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut s = MemoryBackend::new();
+        let mut tx = s.start_transaction().unwrap();
+        tx.replace_code(source).unwrap();
+        apply_init(&module, &mut tx, &mut rng).unwrap();
+        tx.commit().unwrap();
+
+        // Both instances run on the same snapshot, as `sim_step` in two
+        // runners would.
+        let block = &module.blocks[0];
+        let mut txs = Vec::new();
+        for k in &["a", "b"] {
+            let mut tx = s.start_optimistic().unwrap();
+            let mut names = BoundNames::new();
+            names.insert("k".to_string(), Value::Blob(str2blob(k)));
+            tx.forget_reads();
+            reread_eligibility(block, &tx, &names, &mut rng);
+            exec_block(block, &mut tx, &names, &mut rng).unwrap();
+            txs.push(tx);
+        }
+        for tx in txs {
+            tx.commit().unwrap();
+        }
 
-    let mut eligible_blocks = find_eligible_blocks(&code, &tx);
-    if eligible_blocks.len() == 0 {
-        return Ok(StepOutcome::Deadlock);
+        let m = s.start_transaction().unwrap().read_memory(&vec![Value::Blob(str2blob("m"))]).unwrap().unwrap();
+        assert_eq!(format!("{}", m), "{\"a\" |-> 1, \"b\" |-> 1}");
     }
-    // println!("eligible blocks ~~> {}", blocks.len());
-    let i: usize = rng.gen_range(0..eligible_blocks.len());
-    let (block_index, names) = eligible_blocks.swap_remove(i);
-    let block = &code.blocks[block_index];
-    exec_block(block, &mut tx, &names)?;
-    tx.commit()?;
-    return Ok(StepOutcome::TriggeredBlock(block.name.clone(), names));
 }
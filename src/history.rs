@@ -0,0 +1,103 @@
+// Rendering pmemory's history for `ppppl log` and `ppppl diff`.
+
+use crate::storage::Commit;
+use crate::schema::describe_path;
+use crate::eval::is_hidden_key;
+use crate::syntax::Value;
+
+use std::convert::TryFrom;
+
+
+/// One line describing `commit`: its version, when it happened, what caused
+/// it, and the paths it wrote.
+pub fn describe_commit(commit: &Commit) -> String {
+    let mut res = format!("{:>6}  {}  ", commit.version, describe_time(&commit.now));
+    match &commit.cause {
+        Some(cause) => { res.push_str(&cause.to_string()); }
+        None => { res.push_str("(unknown)"); }
+    }
+    if commit.code.is_some() {
+        res.push_str(" (new program)");
+    }
+    // NOTE: timers aren't the program's data, so writes to them aren't
+    // listed.
+    let paths: Vec<String> = commit.writes.iter()
+        .filter(|(path, _)| !path.first().map_or(false, is_hidden_key))
+        .map(|(path, _)| describe_path(path))
+        .collect();
+    if !paths.is_empty() {
+        res.push_str(&format!(": {}", paths.join(", ")));
+    }
+    return res;
+}
+
+/// What changed between two versions of pmemory, one line per path: `+` for
+/// something added, `-` for something removed, and `old -> new` for a
+/// change.
+pub fn diff(old_code: &Option<String>, old: &Value, new_code: &Option<String>, new: &Value) -> Vec<String> {
+    let mut res = Vec::new();
+    if old_code != new_code {
+        res.push("the program changed".to_string());
+    }
+    diff_at(&mut Vec::new(), old, new, &mut res);
+    return res;
+}
+
+fn diff_at(path: &mut Vec<Value>, old: &Value, new: &Value, res: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Dict(old_mapping), Value::Dict(new_mapping)) => {
+            // NOTE: dicts that weren't changed are shared, so this only
+            // visits the paths that were.
+            if old_mapping.ptr_eq(new_mapping) {
+                return;
+            }
+            for (k, old_entry) in old_mapping {
+                if path.is_empty() && is_hidden_key(k) {
+                    continue;
+                }
+                path.push(k.clone());
+                match new_mapping.get(k) {
+                    Some(new_entry) => diff_at(path, old_entry, new_entry, res),
+                    None => res.push(format!("- {}: {}", describe_path(path), old_entry)),
+                }
+                path.pop();
+            }
+            for (k, new_entry) in new_mapping {
+                if !old_mapping.contains_key(k) && !(path.is_empty() && is_hidden_key(k)) {
+                    path.push(k.clone());
+                    res.push(format!("+ {}: {}", describe_path(path), new_entry));
+                    path.pop();
+                }
+            }
+        }
+        _ => {
+            if old != new {
+                res.push(format!("  {}: {} -> {}", describe_path(path), old, new));
+            }
+        }
+    }
+}
+
+// A timestamp in milliseconds since the Unix epoch, as UTC.
+fn describe_time(millis: &num_bigint::BigInt) -> String {
+    let millis = match i64::try_from(millis) {
+        Ok(millis) => millis,
+        Err(_) => { return format!("{}ms", millis); }
+    };
+    let secs = millis.div_euclid(1000);
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // NOTE: days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs / 60 % 60, secs % 60);
+}
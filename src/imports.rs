@@ -0,0 +1,254 @@
+// Multi-file programs.
+//
+// Pmemory only holds one program, so `import` is resolved at load time: every
+// imported file is spliced into a single flattened source text, which is what
+// gets stored.  `import "f.pppl" as q;` renames the blocks of `f.pppl` from
+// `name` to `q.name` (and its templates, instances and processes
+// likewise).
+
+use crate::parse::{ModuleParser, RawModuleParser};
+use crate::syntax::{Item, SourceSpan};
+
+use lalrpop_util::ParseError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+#[derive(Debug)]
+pub enum ImportError {
+    CannotRead(PathBuf, io::Error),
+    /// Where (`file:line:col`, or just the file) and what the error is.
+    CannotParse(String, String),
+    ImportCycle(PathBuf),
+    /// Two files (or one file, twice) define a block, template, instance or
+    /// process with this name.
+    DuplicateBlock(String, PathBuf, PathBuf),
+}
+
+/// A flattened program, plus enough information to map positions in it back
+/// to the files it came from.
+pub struct Flattened {
+    pub code: String,
+
+    files: Vec<(PathBuf, String)>,
+
+    // (start in `code`, length, index into `files`, start in that file)
+    segments: Vec<(usize, usize, usize, usize)>,
+}
+
+impl Flattened {
+
+    // The file a position in the flattened code came from, and the
+    // corresponding position in that file.
+    fn resolve(&self, offset: usize) -> Option<(&Path, &str, usize)> {
+        for (start, len, file_index, file_start) in &self.segments {
+            if *start <= offset && offset < start + len {
+                let (path, source) = &self.files[*file_index];
+                return Some((path, source, file_start + (offset - start)));
+            }
+        }
+        return None;
+    }
+
+    /// Describe a position in the flattened code as `file:line:col`.
+    pub fn location(&self, span: &SourceSpan) -> String {
+        match self.resolve(span.start) {
+            Some((path, source, offset)) => describe_position(path, source, offset),
+            None => format!("<flattened program>:{}", span.start),
+        }
+    }
+
+    /// The line a span starts on, with the span underlined.
+    pub fn excerpt(&self, span: &SourceSpan) -> String {
+        match self.resolve(span.start) {
+            Some((_, source, offset)) => {
+                let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+                let line_end = source[offset..].find('\n').map(|i| offset + i).unwrap_or(source.len());
+                let width = (span.end - span.start).min(line_end - offset).max(1);
+                format!("    {}\n    {}{}", &source[line_start..line_end], " ".repeat(offset - line_start), "^".repeat(width))
+            }
+            None => String::new(),
+        }
+    }
+
+}
+
+// `file:line:col` for a position in `source`, the text of `path`.
+fn describe_position(path: &Path, source: &str, offset: usize) -> String {
+    let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_number = source[..offset].matches('\n').count() + 1;
+    return format!("{}:{}:{}", path.display(), line_number, offset - line_start + 1);
+}
+
+// Where a parse error is (if the parser knows) and what it is.
+fn describe_parse_error<T: Display>(e: ParseError<usize, T, String>) -> (Option<usize>, String) {
+    match e {
+        ParseError::InvalidToken { location } => (Some(location), "unrecognized character".to_string()),
+        ParseError::UnrecognizedEOF { location, expected } =>
+            (Some(location), format!("unexpected end of file; expected one of {}", expected.join(" "))),
+        ParseError::UnrecognizedToken { token: (start, t, _), expected } =>
+            (Some(start), format!("unexpected `{}`; expected one of {}", t, expected.join(" "))),
+        ParseError::ExtraToken { token: (start, t, _) } => (Some(start), format!("unexpected `{}`", t)),
+        ParseError::User { error } => (None, error),
+    }
+}
+
+struct Flattener {
+    // files currently being flattened, to detect cycles
+    stack: Vec<PathBuf>,
+
+    // (file, prefix) pairs already spliced in, so diamond imports are
+    // included once
+    done: BTreeSet<(PathBuf, String)>,
+
+    // which file each (prefixed) block, template, instance and process name
+    // came from
+    origins: BTreeMap<(&'static str, String), PathBuf>,
+
+    out: Flattened,
+}
+
+/// Byte offset of the name after the first `keyword` in `text` (outside of
+/// string literals), e.g. the block name after `def`.
+fn find_name(text: &str, keyword: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if in_string {
+            match bytes[i] {
+                b'\\' => { i += 1; }
+                b'"' => { in_string = false; }
+                _ => { }
+            }
+        } else if bytes[i] == b'"' {
+            in_string = true;
+        } else if bytes[i..].starts_with(keyword.as_bytes()) && (i == 0 || !(bytes[i-1].is_ascii_alphanumeric() || bytes[i-1] == b'_')) {
+            let rest = &text[i+keyword.len()..];
+            let trimmed = rest.trim_start();
+            if trimmed.len() < rest.len() {
+                return Some(text.len() - trimmed.len());
+            }
+        }
+        i += 1;
+    }
+    return None;
+}
+
+impl Flattener {
+
+    fn flatten(&mut self, path: &Path, prefix: &str) -> Result<(), ImportError> {
+        let path = fs::canonicalize(path).map_err(|e| ImportError::CannotRead(path.to_path_buf(), e))?;
+        if self.stack.contains(&path) {
+            return Err(ImportError::ImportCycle(path));
+        }
+        if !self.done.insert((path.clone(), prefix.to_string())) {
+            return Ok(());
+        }
+
+        let source = fs::read_to_string(&path).map_err(|e| ImportError::CannotRead(path.clone(), e))?;
+        let (imports, items) = match RawModuleParser::new().parse(&source) {
+            Ok(raw) => raw,
+            Err(e) => {
+                let (offset, message) = describe_parse_error(e);
+                let location = match offset {
+                    Some(offset) => describe_position(&path, &source, offset),
+                    None => path.display().to_string(),
+                };
+                return Err(ImportError::CannotParse(location, message));
+            }
+        };
+
+        self.stack.push(path.clone());
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for import in &imports {
+            let child_prefix = match &import.alias {
+                Some(alias) => format!("{}{}.", prefix, alias),
+                None => prefix.to_string(),
+            };
+            self.flatten(&dir.join(&import.path), &child_prefix)?;
+        }
+        self.stack.pop();
+
+        // NOTE: the file's text is copied as is, except that the prefix goes in
+        // front of the names it defines, and of the templates its instances
+        // refer to, which were renamed the same way.
+        let mut name_starts = Vec::new();
+        for item in &items {
+            let (kind, keyword, start, name) = match item {
+                Item::Block(b) => ("block", "def", b.annotation.start, &b.name),
+                Item::Template(t) => ("template", "template", t.annotation.start, &t.name),
+                Item::Instance(i) => ("instance", "instance", i.annotation.start, &i.name),
+                Item::Process(p) => ("process", "process", p.annotation.start, &p.name),
+                _ => { continue; }
+            };
+            let full_name = format!("{}{}", prefix, name);
+            if let Some(other) = self.origins.insert((kind, full_name.clone()), path.clone()) {
+                return Err(ImportError::DuplicateBlock(full_name, other, path));
+            }
+            let name_start = start + find_name(&source[start..], keyword).unwrap();
+            name_starts.push(name_start);
+            if let Item::Instance(_) = item {
+                let rest = &source[name_start..];
+                let after_equals = &rest[rest.find('=').unwrap() + 1 ..];
+                name_starts.push(source.len() - after_equals.trim_start().len());
+            }
+        }
+
+        // NOTE: imports always come first, so everything after the last one
+        // is the file's own definitions.
+        let mut copied = match imports.last() {
+            Some(import) => import.annotation.end,
+            None => 0,
+        };
+        let out = &mut self.out;
+        let file_index = out.files.len();
+        if !prefix.is_empty() {
+            for name_start in name_starts {
+                out.segments.push((out.code.len(), name_start - copied, file_index, copied));
+                out.code.push_str(&source[copied .. name_start]);
+                out.code.push_str(prefix);
+                copied = name_start;
+            }
+        }
+        out.segments.push((out.code.len(), source.len() - copied, file_index, copied));
+        out.code.push_str(&source[copied ..]);
+        out.code.push_str("\n\n");
+        out.files.push((path, source));
+
+        return Ok(());
+    }
+
+}
+
+/// Resolve all the imports in the given file (relative to the file that
+/// contains them) and produce a single import-free program.
+pub fn flatten(path: &Path) -> Result<Flattened, ImportError> {
+    let mut f = Flattener {
+        stack: Vec::new(),
+        done: BTreeSet::new(),
+        origins: BTreeMap::new(),
+        out: Flattened {
+            code: String::new(),
+            files: Vec::new(),
+            segments: Vec::new(),
+        },
+    };
+    f.flatten(path, "")?;
+
+    // Check the program as a whole, since instances can refer to templates in
+    // other files.
+    if let Err(e) = ModuleParser::new().parse(&f.out.code) {
+        let (offset, message) = describe_parse_error(e);
+        let location = match offset {
+            Some(offset) => f.out.location(&SourceSpan { start: offset, end: offset }),
+            None => path.display().to_string(),
+        };
+        return Err(ImportError::CannotParse(location, message));
+    }
+
+    return Ok(f.out);
+}
@@ -1,106 +1,482 @@
 mod syntax;
 mod eval;
 mod storage;
+mod imports;
+mod desugar;
+mod schema;
+mod typecheck;
+mod resolve;
+mod scheduler;
+mod runner;
+mod history;
 
-use storage::{Storage, Transaction};
+use storage::{Backend, Transaction, History, Cause, StorageError, SqliteBackend, LogBackend, MemoryBackend};
+use imports::ImportError;
 use parse::{ModuleParser, ExpParser, AssignParser};
-use std::fs;
-use rand;
-use clap::{App, Arg, SubCommand};
+use std::path::{Path, PathBuf};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use clap::{App, Arg, ArgMatches, SubCommand};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+use std::process;
 
+#[macro_use] extern crate clap;
 #[macro_use] extern crate lalrpop_util;
 lalrpop_mod!(pub parse); // synthesized by LALRPOP
 
-fn load(mut tx: Transaction, filename: &str, code: &str) {
-    ModuleParser::new().parse(code).unwrap(); // check parseability
-    tx.replace_code(code).unwrap();
-    tx.commit().unwrap();
-    println!("Loaded {}", filename);
+// Returns true if the program is well-typed.  Warnings don't count.
+fn check(program: &imports::Flattened, module: &syntax::Module<syntax::SourceSpan>) -> bool {
+    for d in &resolve::check(module) {
+        println!("{}: warning: {}", program.location(&d.span), d.message);
+        println!("{}", program.excerpt(&d.span));
+    }
+    let diagnostics = typecheck::check(module);
+    for d in &diagnostics {
+        println!("{}: error: {}", program.location(&d.span), d.message);
+        println!("{}", program.excerpt(&d.span));
+    }
+    return diagnostics.is_empty();
 }
 
-fn run(storage: &mut Storage) {
-    let mut rng = rand::thread_rng();
-    loop {
-        match eval::sim_step(storage, &mut rng) {
-            Ok(eval::StepOutcome::Deadlock) => {
-                println!("deadlock");
+// The program in `filename`, with its imports resolved.
+fn flatten(filename: &str) -> imports::Flattened {
+    match imports::flatten(Path::new(filename)) {
+        Ok(program) => program,
+        Err(ImportError::CannotRead(path, e)) => {
+            println!("Can't read {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        Err(ImportError::CannotParse(location, message)) => {
+            println!("{}: error: {}", location, message);
+            process::exit(1);
+        }
+        Err(ImportError::ImportCycle(path)) => {
+            println!("{} imports itself (possibly through other files)", path.display());
+            process::exit(1);
+        }
+        Err(ImportError::DuplicateBlock(name, first, second)) => {
+            if first == second {
+                println!("`{}` is defined twice in {}", name, first.display());
+            } else {
+                println!("`{}` is defined in both {} and {}", name, first.display(), second.display());
             }
-            Ok(eval::StepOutcome::TriggeredBlock(name, args)) => {
-                if args.is_empty() {
-                    println!("triggered: `{}`", name);
-                } else {
-                    println!("triggered: `{}` with arguments {:?}", name, args);
-                }
+            process::exit(1);
+        }
+    }
+}
+
+fn load<T: Transaction>(mut tx: T, filename: &str, reset: bool, force: bool, rng: &mut dyn RngCore) {
+    let program = flatten(filename); // also checks parseability
+    let module = ModuleParser::new().parse(&program.code).unwrap();
+    if !check(&program, &module) && !force {
+        println!("Refusing to load {} (use --force to load it anyway)", filename);
+        process::exit(1);
+    }
+    tx.set_cause(Cause::Load(filename.to_string()));
+    tx.replace_code(&program.code).unwrap();
+    if reset {
+        eval::do_assignment(&Vec::new(), &syntax::Value::Dict(im::OrdMap::new()), &mut tx).unwrap();
+    }
+    eval::apply_init(&module, &mut tx, rng).unwrap();
+    match tx.commit() {
+        Ok(()) => println!("Loaded {}", filename),
+        Err(StorageError::SchemaViolation(message)) => {
+            println!("Refusing to load {}: pmemory wouldn't fit its schema ({}); use --reset to start from its initial values", filename, message);
+            process::exit(1);
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+// `ppppl run`, on pmemory in `s`.  Returns the exit code.
+fn run<B: Backend>(s: &mut B, run_args: &ArgMatches) -> i32 {
+    // NOTE: the seed is always printed, so that any run can be replayed
+    // (from the same initial memory) with `--seed`.
+    let seed = if run_args.is_present("seed") {
+        value_t!(run_args, "seed", u64).unwrap_or_else(|e| e.exit())
+    } else {
+        rand::thread_rng().gen()
+    };
+    println!("seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let threads = value_t!(run_args, "threads", usize).unwrap_or_else(|e| e.exit());
+    if threads == 0 {
+        clap::Error::value_validation_auto("--threads must be at least 1".to_string()).exit();
+    }
+    let limits = runner::Limits {
+        max_steps: if run_args.is_present("max-steps") { Some(value_t!(run_args, "max-steps", u64).unwrap_or_else(|e| e.exit())) } else { None },
+        until: run_args.value_of("until").map(|e| match ExpParser::new().parse(e) {
+            Ok(e) => e,
+            Err(err) => clap::Error::value_validation_auto(format!("bad --until `{}`: {}", e, err)).exit(),
+        }),
+        timeout: run_args.value_of("timeout").map(|t| match syntax::parse_duration_millis(t).and_then(|ms| u64::try_from(ms).ok()) {
+            Some(ms) => Duration::from_millis(ms),
+            None => clap::Error::value_validation_auto(format!("bad --timeout `{}` (try e.g. 30s or 5m)", t)).exit(),
+        }),
+        exit_on_deadlock: run_args.is_present("exit-on-deadlock"),
+    };
+    if let Some(filename) = run_args.value_of("INPUT") {
+        load(s.start_transaction().unwrap(), &filename, false, run_args.is_present("force"), &mut rng);
+    }
+    return runner::run(s, run_args.value_of("policy").unwrap(), seed, threads, limits);
+}
+
+// Pmemory as of `version`, or exit if there's no record of it.
+fn state_at<B: History>(s: &mut B, version: i64) -> (Option<String>, syntax::Value) {
+    match s.state_at(version) {
+        Ok(state) => state,
+        Err(StorageError::NoHistory(v)) => {
+            println!("There's no record of version {} (`ppppl log` lists the versions there are)", v);
+            process::exit(1);
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+// Exit unless there are earlier versions of pmemory to go back to.
+// `command` says what can't be done, for the messages.
+fn require_history<B: History>(s: &mut B, command: &str) {
+    if !s.history_enabled().unwrap() {
+        println!("Can't {}: history is off, so no earlier versions were kept (`ppppl history on` keeps them from now on)", command);
+        process::exit(1);
+    }
+    if s.current_version().unwrap() == 0 {
+        println!("Can't {}: pmemory hasn't changed since it was created", command);
+        process::exit(1);
+    }
+}
+
+// `ppppl rollback` and `ppppl undo`: commit pmemory as it was at `version`
+// (and, with `code`, the program too) as a new version.
+fn rollback<B: History>(s: &mut B, version: i64, code: bool) {
+    let (old_code, old) = state_at(s, version);
+    let mut tx = s.start_transaction().unwrap();
+    tx.set_cause(Cause::Rollback(version));
+    if code {
+        match old_code {
+            Some(old_code) => tx.replace_code(&old_code).unwrap(),
+            None => {
+                println!("There was no program at version {}", version);
+                process::exit(1);
             }
-            Err(e) => {
-                println!("fault: {:?}", e);
+        }
+    }
+    tx.write_memory(&Vec::new(), &old).unwrap();
+    match tx.commit() {
+        Ok(()) => println!("Rolled back to version {} (as version {})", version, s.current_version().unwrap()),
+        Err(StorageError::SchemaViolation(message)) => {
+            println!("Version {} doesn't fit the current program's schema ({}); use --code to restore its program too", version, message);
+            process::exit(1);
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+fn version_arg(args: &ArgMatches, name: &str) -> i64 {
+    return value_t!(args, name, i64).unwrap_or_else(|e| e.exit());
+}
+
+// The commands that need pmemory, on pmemory in `s`.
+fn command<B: History>(mut s: B, matches: &ArgMatches) {
+    let no_bound_names = BTreeMap::new();
+
+    if let Some(load_args) = matches.subcommand_matches("load") {
+        let filename = load_args.value_of("INPUT").unwrap();
+        load(s.start_transaction().unwrap(), &filename, load_args.is_present("reset"), load_args.is_present("force"), &mut rand::thread_rng());
+    } else if let Some(run_args) = matches.subcommand_matches("run") {
+        process::exit(run(&mut s, run_args));
+    } else if let Some(read_args) = matches.subcommand_matches("read") {
+        let e = ExpParser::new().parse(read_args.value_of("EXPR").unwrap()).unwrap();
+        // NOTE: an earlier version is read from a copy of it in memory.
+        let tx = if read_args.is_present("at") {
+            let (code, mem) = state_at(&mut s, version_arg(read_args, "at"));
+            Box::new(MemoryBackend::with(code, mem).start_transaction().unwrap()) as Box<dyn Transaction>
+        } else {
+            Box::new(s.start_transaction().unwrap())
+        };
+        let e = desugar::resolve_constants(&e, &tx.read_code().unwrap().constants).unwrap();
+        let mut res = eval::eval(&e, &*tx, &no_bound_names, &mut rand::thread_rng()).unwrap();
+        if let syntax::Exp::Root(_) = e {
+            res = eval::without_hidden_keys(res);
+        }
+        println!("{}", res);
+    } else if let Some(write_args) = matches.subcommand_matches("write") {
+        let assignment = write_args.value_of("ASSIGNMENT").unwrap();
+        let (lval, e) = AssignParser::new().parse(assignment).unwrap();
+        let mut tx = s.start_transaction().unwrap();
+        tx.set_cause(Cause::Write(assignment.to_string()));
+        let (lval, e) = desugar::resolve_constants_in_assignment(&lval, &e, &tx.read_code().unwrap().constants).unwrap();
+        let mut rng = rand::thread_rng();
+        eval::do_assignment(
+            &eval::eval_lval(&lval, &tx, &no_bound_names, &mut rng).unwrap(),
+            &eval::eval(&e, &tx, &no_bound_names, &mut rng).unwrap(),
+            &mut tx).unwrap();
+        match tx.commit() {
+            Ok(()) => println!("So it is."),
+            Err(StorageError::SchemaViolation(message)) => {
+                println!("Refusing to write: pmemory wouldn't fit the schema ({})", message);
+                process::exit(1);
             }
+            Err(e) => panic!("{:?}", e),
+        }
+    } else if let Some(log_args) = matches.subcommand_matches("log") {
+        if !s.history_enabled().unwrap() {
+            println!("History is off, so there's nothing to show (turn it on with `ppppl history on`)");
+            return;
+        }
+        let mut commits = s.commits(0).unwrap();
+        if log_args.is_present("last") {
+            let last = value_t!(log_args, "last", usize).unwrap_or_else(|e| e.exit());
+            commits.drain(.. commits.len().saturating_sub(last));
+        }
+        for commit in &commits {
+            println!("{}", history::describe_commit(commit));
+        }
+    } else if let Some(diff_args) = matches.subcommand_matches("diff") {
+        let (old_code, old) = state_at(&mut s, version_arg(diff_args, "V1"));
+        let (new_code, new) = state_at(&mut s, version_arg(diff_args, "V2"));
+        let lines = history::diff(&old_code, &old, &new_code, &new);
+        if lines.is_empty() {
+            println!("No changes.");
+        }
+        for line in lines {
+            println!("{}", line);
+        }
+    } else if let Some(rollback_args) = matches.subcommand_matches("rollback") {
+        let version = version_arg(rollback_args, "to");
+        require_history(&mut s, "roll back");
+        rollback(&mut s, version, rollback_args.is_present("code"));
+    } else if let Some(undo_args) = matches.subcommand_matches("undo") {
+        require_history(&mut s, "undo");
+        let version = s.current_version().unwrap() - 1;
+        rollback(&mut s, version, undo_args.is_present("code"));
+    } else if let Some(history_args) = matches.subcommand_matches("history") {
+        match history_args.value_of("SETTING") {
+            Some(setting) => match s.set_history(setting == "on") {
+                Ok(()) => println!("History is {}.", setting),
+                Err(StorageError::HistoryIsAlwaysOn) => {
+                    println!("This backend always keeps history.");
+                    process::exit(1);
+                }
+                Err(e) => panic!("{:?}", e),
+            },
+            None => println!("History is {}.", if s.history_enabled().unwrap() { "on" } else { "off" }),
+        }
+    }
+}
+
+// Where pmemory is stored, going by --db and --space.
+fn location(matches: &ArgMatches, extension: &str) -> PathBuf {
+    let res = match (matches.value_of("db"), matches.value_of("space")) {
+        (Some(path), _) => Ok(PathBuf::from(path)),
+        (None, Some(name)) => storage::space_location(name, extension),
+        (None, None) => storage::default_location(extension),
+    };
+    match res {
+        Ok(location) => location,
+        Err(StorageError::NoHomeDirectory) => {
+            println!("Can't find your home directory to keep pmemory in (use --db to say where it is)");
+            process::exit(1);
+        }
+        Err(StorageError::BadSpaceName(name)) => {
+            println!("`{}` isn't a valid space name (use letters, digits, - and _)", name);
+            process::exit(1);
         }
+        Err(StorageError::CannotCreateDirectory(dir, message)) => {
+            println!("Can't create {}: {}", dir.display(), message);
+            process::exit(1);
+        }
+        Err(e) => panic!("{:?}", e),
     }
 }
 
 fn main() {
     let matches = App::new("ppppl")
         .about("Interface to the Persistent Parallel Programming Language")
+        .arg(Arg::with_name("db")
+            .long("db")
+            .takes_value(true)
+            .global(true)
+            .conflicts_with("space")
+            .help("The file that holds pmemory (default: $PPPL_DB, or ~/.pppl.db)"))
+        .arg(Arg::with_name("space")
+            .long("space")
+            .takes_value(true)
+            .global(true)
+            .help("Use the named pmemory NAME, stored in ~/.pppl/NAME.db"))
+        .arg(Arg::with_name("backend")
+            .long("backend")
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["sqlite", "log"])
+            .default_value("sqlite")
+            .help("How pmemory is stored: a SQLite database, or an append-only log (in .log files rather than .db)"))
         .subcommand(SubCommand::with_name("load")
             .arg(Arg::with_name("INPUT")
                 .help("The input file to load")
                 .required(true)
+                .index(1))
+            .arg(Arg::with_name("reset")
+                .long("reset")
+                .help("Replace pmemory with the program's initial values"))
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Load the program even if it has type errors")))
+        .subcommand(SubCommand::with_name("check")
+            .arg(Arg::with_name("INPUT")
+                .help("The input file to type-check")
+                .required(true)
                 .index(1)))
         .subcommand(SubCommand::with_name("run")
             .arg(Arg::with_name("INPUT")
                 .help("An input file to load before running")
                 .required(false)
-                .index(1)))
+                .index(1))
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Load the input file even if it has type errors"))
+            .arg(Arg::with_name("policy")
+                .long("policy")
+                .takes_value(true)
+                .possible_values(scheduler::POLICIES)
+                .default_value("random")
+                .help("How to choose among the blocks that can run"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("Seed for the random number generator, to replay an earlier run"))
+            .arg(Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true)
+                .help("Stop after running this many blocks (exit code 3)"))
+            .arg(Arg::with_name("until")
+                .long("until")
+                .takes_value(true)
+                .help("Stop once this expression is true (exit code 0)"))
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .help("Stop after this long, e.g. 30s or 5m (exit code 4)"))
+            .arg(Arg::with_name("exit-on-deadlock")
+                .long("exit-on-deadlock")
+                .help("Stop when no block can ever run again (exit code 2)"))
+            .arg(Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .default_value("1")
+                .help("How many worker threads to run blocks on"))
+            .arg(Arg::with_name("in-memory")
+                .long("in-memory")
+                .requires("INPUT")
+                .help("Run the input file in a fresh pmemory that is never saved, and print it at the end")))
         .subcommand(SubCommand::with_name("read")
             .arg(Arg::with_name("EXPR")
                 .help("The expression to evaluate")
                 .required(true)
-                .index(1)))
+                .index(1))
+            .arg(Arg::with_name("at")
+                .long("at")
+                .takes_value(true)
+                .value_name("VERSION")
+                .help("Evaluate it against an earlier version of pmemory (needs history)")))
         .subcommand(SubCommand::with_name("write")
             .arg(Arg::with_name("ASSIGNMENT")
                 .help("An assignment statement to evaluate")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("log")
+            .about("List the recorded versions of pmemory and what made each one")
+            .arg(Arg::with_name("last")
+                .long("last")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only list the last N versions")))
+        .subcommand(SubCommand::with_name("diff")
+            .about("Show what changed in pmemory between two versions")
+            .arg(Arg::with_name("V1")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("V2")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("rollback")
+            .about("Make pmemory what it was at an earlier version, as a new version")
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .value_name("VERSION")
+                .required(true)
+                .help("The version to go back to"))
+            .arg(Arg::with_name("code")
+                .long("code")
+                .help("Restore that version's program too")))
+        .subcommand(SubCommand::with_name("undo")
+            .about("Undo the last change to pmemory, as a new version")
+            .arg(Arg::with_name("code")
+                .long("code")
+                .help("Restore the previous version's program too")))
+        .subcommand(SubCommand::with_name("history")
+            .about("Show whether every version of pmemory is kept, or turn that on or off")
+            .arg(Arg::with_name("SETTING")
+                .possible_values(&["on", "off"])
+                .index(1)
+                .help("Turning history off forgets what was kept")))
         .get_matches();
 
-    let no_bound_names = BTreeMap::new();
+    let extension = if matches.value_of("backend") == Some("log") { "log" } else { "db" };
 
-    if let Some(load_args) = matches.subcommand_matches("load") {
-        let mut s = Storage::open().unwrap();
-        let filename = load_args.value_of("INPUT").unwrap();
-        load(
-            s.start_transaction().unwrap(),
-            &filename,
-            &fs::read_to_string(filename).unwrap());
-    } else if let Some(run_args) = matches.subcommand_matches("run") {
-        let mut s = Storage::open().unwrap();
-        if let Some(filename) = run_args.value_of("INPUT") {
-            load(
-                s.start_transaction().unwrap(),
-                &filename,
-                &fs::read_to_string(filename).unwrap());
-        }
-        run(&mut s);
-    } else if let Some(read_args) = matches.subcommand_matches("read") {
-        let mut s = Storage::open().unwrap();
-        let e = ExpParser::new().parse(read_args.value_of("EXPR").unwrap()).unwrap();
-        let tx = s.start_transaction().unwrap();
-        let res = eval::eval(&e, &tx, &no_bound_names).unwrap();
-        println!("{}", res);
-    } else if let Some(write_args) = matches.subcommand_matches("write") {
-        let mut s = Storage::open().unwrap();
-        let (lval, e) = AssignParser::new().parse(write_args.value_of("ASSIGNMENT").unwrap()).unwrap();
-        let mut tx = s.start_transaction().unwrap();
-        eval::do_assignment(
-            &eval::eval_lval(&lval, &tx, &no_bound_names).unwrap(),
-            &eval::eval(&e, &tx, &no_bound_names).unwrap(),
-            &mut tx).unwrap();
-        tx.commit().unwrap();
-        println!("So it is.");
-    } else {
+    if let Some(check_args) = matches.subcommand_matches("check") {
+        let filename = check_args.value_of("INPUT").unwrap();
+        let program = flatten(filename);
+        let module = ModuleParser::new().parse(&program.code).unwrap();
+        if check(&program, &module) {
+            println!("{} is well-typed", filename);
+        } else {
+            process::exit(1);
+        }
+    } else if let Some(run_args) = matches.subcommand_matches("run").filter(|args| args.is_present("in-memory")) {
+        let mut s = MemoryBackend::new();
+        let exit_code = run(&mut s, run_args);
+        println!("{}", eval::without_hidden_keys(s.start_transaction().unwrap().read_memory(&Vec::new()).unwrap().unwrap()));
+        process::exit(exit_code);
+    } else if matches.subcommand_name().is_none() {
         println!("You didn't tell me anything to do.");
+    } else if extension == "log" {
+        command(LogBackend::open(&location(&matches, extension)).unwrap(), &matches);
+    } else {
+        command(SqliteBackend::open(&location(&matches, extension)).unwrap(), &matches);
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `source` to a scratch file and returns its path.
+    fn scratch_file(name: &str, source: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ppppl-test-{}-{}", process::id(), name));
+        std::fs::write(&path, source).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn badly_typed_programs_only_load_with_force() {
+        let path = scratch_file("badly-typed.pppl", "init {\n    x := 0;\n}\n\ndef f:\n    require x == \"a\";\n    x := 1;\n");
+        let filename = path.to_str().unwrap();
+
+        // `load` refuses exactly when this is false.
+        let program = flatten(filename);
+        let module = ModuleParser::new().parse(&program.code).unwrap();
+        assert!(!check(&program, &module));
+
+        let mut s = MemoryBackend::new();
+        load(s.start_transaction().unwrap(), filename, false, true, &mut StdRng::seed_from_u64(0));
+        let tx = s.start_transaction().unwrap();
+        assert_eq!(tx.read_code().unwrap().blocks[0].name, "f");
+        assert_eq!(tx.read_memory(&vec![syntax::Value::Blob(syntax::str2blob("x"))]).unwrap(), Some(syntax::Value::Int(0.into())));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+// Name resolution warnings.
+//
+// A bare name `x` means the parameter (or `choose` variable) `x` if one is in
+// scope, and the memory key `.x` otherwise.  So a parameter silently hides a
+// memory key of the same name.  That's legal, but it's usually a mistake, so
+// `ppppl load` and `ppppl check` warn about it.
+
+use crate::syntax::*;
+use crate::typecheck::Diagnostic;
+
+use std::collections::BTreeSet;
+
+
+/// The memory keys that `e` refers to by name (`x` or `.x`).
+fn exp_keys<A>(e: &Exp<A>, bound: &BTreeSet<String>, out: &mut BTreeSet<String>) {
+    match e {
+        Exp::Name(_, n) => {
+            if !bound.contains(n) {
+                out.insert(n.clone());
+            }
+        }
+        Exp::Root(_) | Exp::Literal(_, _) => { }
+        Exp::Unary(_, _, e1) => { exp_keys(e1, bound, out); }
+        Exp::Binary(_, BinaryOp::INDEX, e1, e2) => match (&**e1, &**e2) {
+            (Exp::Root(_), Exp::Literal(_, Value::Blob(key))) => {
+                out.insert(String::from_utf8_lossy(key).into_owned());
+            }
+            _ => { exp_keys(e1, bound, out); exp_keys(e2, bound, out); }
+        },
+        Exp::Binary(_, _, e1, e2) => { exp_keys(e1, bound, out); exp_keys(e2, bound, out); }
+        Exp::Ternary(_, _, e1, e2, e3) => { exp_keys(e1, bound, out); exp_keys(e2, bound, out); exp_keys(e3, bound, out); }
+        Exp::Call(_, _, args) => {
+            for arg in args {
+                exp_keys(arg, bound, out);
+            }
+        }
+        Exp::Choose(_, k, e1, cond) => {
+            exp_keys(e1, bound, out);
+            if let Some(cond) = cond {
+                let mut inner = bound.clone();
+                inner.insert(k.clone());
+                exp_keys(cond, &inner, out);
+            }
+        }
+    }
+}
+
+fn lval_keys<A>(lv: &LVal<A>, bound: &BTreeSet<String>, out: &mut BTreeSet<String>) {
+    match lv {
+        LVal::Root(_) => { }
+        LVal::Name(_, n) => {
+            if !bound.contains(n) {
+                out.insert(n.clone());
+            }
+        }
+        LVal::Index(_, base, idx) => match (&**base, &**idx) {
+            (LVal::Root(_), Exp::Literal(_, Value::Blob(key))) => {
+                out.insert(String::from_utf8_lossy(key).into_owned());
+            }
+            _ => { lval_keys(base, bound, out); exp_keys(idx, bound, out); }
+        },
+    }
+}
+
+fn block_keys<A>(b: &Block<A>, out: &mut BTreeSet<String>) {
+    let mut bound = BTreeSet::new();
+    for (p, e) in &b.parameters {
+        exp_keys(e, &bound, out);
+        bound.insert(p.clone());
+    }
+    for attr in &b.attributes {
+        match attr {
+            Attribute::Every(_, e) | Attribute::Priority(_, e) => { exp_keys(e, &bound, out); }
+            Attribute::Weight(_, _) | Attribute::Disabled(_) => { }
+        }
+    }
+    for g in &b.guards {
+        exp_keys(g, &bound, out);
+    }
+    for (lv, e) in &b.assignments {
+        lval_keys(lv, &bound, out);
+        exp_keys(e, &bound, out);
+    }
+}
+
+fn warn(out: &mut Vec<Diagnostic>, span: SourceSpan, kind: &str, name: &str) {
+    out.push(Diagnostic {
+        span: span,
+        message: format!("{} `{}` hides the memory key `{}`; write `.{}` to mean the key", kind, name, name, name),
+    });
+}
+
+fn exp_shadows(e: &Exp<SourceSpan>, keys: &BTreeSet<String>, out: &mut Vec<Diagnostic>) {
+    match e {
+        Exp::Root(_) | Exp::Name(_, _) | Exp::Literal(_, _) => { }
+        Exp::Unary(_, _, e1) => { exp_shadows(e1, keys, out); }
+        Exp::Binary(_, _, e1, e2) => { exp_shadows(e1, keys, out); exp_shadows(e2, keys, out); }
+        Exp::Ternary(_, _, e1, e2, e3) => { exp_shadows(e1, keys, out); exp_shadows(e2, keys, out); exp_shadows(e3, keys, out); }
+        Exp::Call(_, _, args) => {
+            for arg in args {
+                exp_shadows(arg, keys, out);
+            }
+        }
+        Exp::Choose(a, k, e1, cond) => {
+            if keys.contains(k) {
+                warn(out, *a, "`choose` variable", k);
+            }
+            exp_shadows(e1, keys, out);
+            if let Some(cond) = cond {
+                exp_shadows(cond, keys, out);
+            }
+        }
+    }
+}
+
+/// Find parameters and `choose` variables with the same name as a memory key
+/// that the program uses elsewhere (or that the schema declares).
+pub fn check(m: &Module<SourceSpan>) -> Vec<Diagnostic> {
+    let mut keys: BTreeSet<String> = m.schema.keys().cloned().collect();
+    for (lv, e) in &m.init {
+        lval_keys(lv, &BTreeSet::new(), &mut keys);
+        exp_keys(e, &BTreeSet::new(), &mut keys);
+    }
+    for b in &m.blocks {
+        block_keys(b, &mut keys);
+    }
+
+    let mut res = Vec::new();
+    for b in &m.blocks {
+        for (p, e) in &b.parameters {
+            if keys.contains(p) {
+                warn(&mut res, *e.annotation(), "parameter", p);
+            }
+            exp_shadows(e, &keys, &mut res);
+        }
+        for attr in &b.attributes {
+            match attr {
+                Attribute::Every(_, e) | Attribute::Priority(_, e) => { exp_shadows(e, &keys, &mut res); }
+                Attribute::Weight(_, _) | Attribute::Disabled(_) => { }
+            }
+        }
+        for g in &b.guards {
+            exp_shadows(g, &keys, &mut res);
+        }
+        for (lv, e) in &b.assignments {
+            exp_shadows(e, &keys, &mut res);
+            let mut lv = lv;
+            while let LVal::Index(_, base, idx) = lv {
+                exp_shadows(idx, &keys, &mut res);
+                lv = base;
+            }
+        }
+    }
+    for (_, e) in &m.init {
+        exp_shadows(e, &keys, &mut res);
+    }
+
+    // NOTE: blocks expanded from the same template or process share spans,
+    // so the same warning can come up several times.
+    res.sort_by(|d1, d2| (d1.span, &d1.message).cmp(&(d2.span, &d2.message)));
+    res.dedup_by(|d1, d2| d1.span == d2.span && d1.message == d2.message);
+    return res;
+}
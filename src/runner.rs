@@ -0,0 +1,215 @@
+// The `ppppl run` loop: worker threads that repeatedly run blocks until one of
+// the `--until`, `--max-steps`, `--timeout` or `--exit-on-deadlock` limits is
+// reached, or the user presses Ctrl-C.
+
+use crate::desugar;
+use crate::eval;
+use crate::scheduler;
+use crate::storage::{Backend, Transaction, Watcher};
+use crate::syntax::{Exp, SourceSpan, Value};
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+
+// Exit codes for `ppppl run`, so that scripts can tell why it stopped.
+pub const EXIT_UNTIL: i32 = 0;
+pub const EXIT_DEADLOCK: i32 = 2;
+pub const EXIT_MAX_STEPS: i32 = 3;
+pub const EXIT_TIMEOUT: i32 = 4;
+pub const EXIT_INTERRUPTED: i32 = 130; // the shell's convention for SIGINT
+
+/// When `ppppl run` should stop.
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub until: Option<Exp<SourceSpan>>,
+    pub timeout: Option<Duration>,
+    pub exit_on_deadlock: bool,
+}
+
+// State shared by all the workers.
+struct Shared {
+    policy: String,
+    max_steps: Option<u64>,
+    until: Option<Exp<SourceSpan>>,
+    stop_at: Option<Instant>,
+    exit_on_deadlock: bool,
+
+    // steps that have been started (and not given back because they didn't
+    // run anything), so that `--max-steps` is exact with several workers
+    steps_started: AtomicU64,
+
+    // set by whichever worker (or the Ctrl-C handler) decides to stop first
+    exit_code: Mutex<Option<i32>>,
+}
+
+impl Shared {
+
+    fn stop(&self, code: i32, why: &str) {
+        let mut exit_code = self.exit_code.lock().unwrap();
+        if exit_code.is_none() {
+            println!("stopping: {}", why);
+            *exit_code = Some(code);
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.exit_code.lock().unwrap().is_some()
+    }
+
+}
+
+fn until_holds<B: Backend>(storage: &mut B, until: &Exp<SourceSpan>, rng: &mut StdRng) -> bool {
+    let tx = storage.start_optimistic().unwrap();
+    match eval::eval(until, &tx, &BTreeMap::new(), rng) {
+        Ok(Value::Bool(b)) => b,
+        _ => false,
+    }
+}
+
+// Sleep until pmemory changes, `deadline` passes, or the run is stopped.
+fn wait<W: Watcher>(shared: &Shared, watcher: &W, version: i64, deadline: Option<Instant>) {
+    // NOTE: wake up now and then to notice Ctrl-C.
+    let poll = Duration::from_millis(100);
+    while !shared.stopped() {
+        let now = Instant::now();
+        if deadline.map_or(false, |t| now >= t) {
+            return;
+        }
+        let until = deadline.map_or(now + poll, |t| t.min(now + poll));
+        if watcher.wait_for_change(version, Some(until)).unwrap() {
+            return;
+        }
+    }
+}
+
+// Returns the number of blocks this worker ran.
+fn worker<B: Backend>(label: String, mut storage: B, mut rng: StdRng, shared: &Shared) -> u64 {
+    let mut scheduler = scheduler::by_name(&shared.policy).unwrap();
+    let watcher = storage.watch().unwrap();
+    let mut code_version = watcher.code_version().unwrap();
+    let mut steps = 0;
+    while !shared.stopped() {
+        // NOTE: nothing else is needed to pick up a new program; every step
+        // reads the current one.
+        let latest = watcher.code_version().unwrap();
+        if latest != code_version {
+            println!("{}picked up a newly loaded program", label);
+            code_version = latest;
+        }
+        if let Some(until) = &shared.until {
+            if until_holds(&mut storage, until, &mut rng) {
+                shared.stop(EXIT_UNTIL, "the --until condition holds");
+                break;
+            }
+        }
+        if shared.stop_at.map_or(false, |t| Instant::now() >= t) {
+            shared.stop(EXIT_TIMEOUT, "timed out");
+            break;
+        }
+        if let Some(max) = shared.max_steps {
+            if shared.steps_started.fetch_add(1, Ordering::SeqCst) >= max {
+                shared.steps_started.fetch_sub(1, Ordering::SeqCst);
+                shared.stop(EXIT_MAX_STEPS, &format!("ran {} steps", max));
+                break;
+            }
+        }
+
+        // NOTE: the version is read before the step, so that a commit that
+        // lands while the step is looking at memory still wakes us up.
+        let version = watcher.version().unwrap();
+        let outcome = eval::sim_step(&mut storage, &mut *scheduler, &mut rng);
+        match &outcome {
+            Ok(eval::StepOutcome::TriggeredBlock(_, _)) => { steps += 1; }
+            _ => {
+                if shared.max_steps.is_some() {
+                    shared.steps_started.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+        match outcome {
+            Ok(eval::StepOutcome::Deadlock) => {
+                if shared.exit_on_deadlock {
+                    println!("{}deadlock", label);
+                    shared.stop(EXIT_DEADLOCK, "deadlock");
+                    break;
+                }
+                println!("{}deadlock; waiting for pmemory to change", label);
+                wait(shared, &watcher, version, shared.stop_at);
+            }
+            Ok(eval::StepOutcome::WaitingUntil(deadline)) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                let millis = Duration::from_millis(u64::try_from(deadline - now).unwrap_or(0));
+                println!("{}waiting {}ms for a timer", label, millis.as_millis());
+                let wake_at = Instant::now() + millis;
+                wait(shared, &watcher, version, Some(shared.stop_at.map_or(wake_at, |t| t.min(wake_at))));
+            }
+            Ok(eval::StepOutcome::TriggeredBlock(name, args)) => {
+                if args.is_empty() {
+                    println!("{}triggered: `{}`", label, name);
+                } else {
+                    println!("{}triggered: `{}` with arguments {:?}", label, name, args);
+                }
+            }
+            Err(e) => {
+                println!("{}fault: {:?}", label, e);
+            }
+        }
+    }
+    return steps;
+}
+
+fn rate(steps: u64, elapsed: Duration) -> f64 {
+    steps as f64 / elapsed.as_secs_f64().max(0.001)
+}
+
+/// Run the program in `storage` on `threads` worker threads until a limit is
+/// reached.  Worker `i` seeds its random number generator with `seed + i`.
+/// Returns the exit code.
+pub fn run<B: Backend>(storage: &B, policy: &str, seed: u64, threads: usize, limits: Limits) -> i32 {
+    let code = storage.clone().start_transaction().unwrap().read_code().unwrap();
+    println!("policy: {}", eval::describe_policy(&code, &*scheduler::by_name(policy).unwrap()));
+
+    let shared = Arc::new(Shared {
+        policy: policy.to_string(),
+        max_steps: limits.max_steps,
+        until: limits.until.as_ref().map(|e| desugar::resolve_constants(e, &code.constants).unwrap()),
+        stop_at: limits.timeout.map(|timeout| Instant::now() + timeout),
+        exit_on_deadlock: limits.exit_on_deadlock,
+        steps_started: AtomicU64::new(0),
+        exit_code: Mutex::new(None),
+    });
+    {
+        let shared = shared.clone();
+        ctrlc::set_handler(move || shared.stop(EXIT_INTERRUPTED, "interrupted")).unwrap();
+    }
+
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for i in 0 .. threads {
+        let label = if threads > 1 { format!("[worker {}] ", i) } else { String::new() };
+        let storage = storage.clone();
+        let rng = StdRng::seed_from_u64(seed.wrapping_add(i as u64));
+        let shared = shared.clone();
+        workers.push(thread::spawn(move || worker(label, storage, rng, &shared)));
+    }
+    let steps: Vec<u64> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    let elapsed = started.elapsed();
+
+    if threads > 1 {
+        for (i, n) in steps.iter().enumerate() {
+            println!("worker {}: {} steps, {:.1} steps/s", i, n, rate(*n, elapsed));
+        }
+    }
+    let total = steps.iter().sum();
+    println!("total: {} steps in {:.2}s, {:.1} steps/s", total, elapsed.as_secs_f64(), rate(total, elapsed));
+
+    let exit_code = shared.exit_code.lock().unwrap();
+    return exit_code.unwrap_or(EXIT_INTERRUPTED);
+}
@@ -0,0 +1,144 @@
+// Policies for choosing which eligible block instance runs next.
+//
+// `eval::sim_step` finds the eligible instances (a block plus a binding of its
+// parameters), keeps only those with the highest `@priority`, and then asks a
+// `Scheduler` to pick one.
+
+use crate::eval::BoundNames;
+use crate::syntax::*;
+
+use rand::{Rng, RngCore};
+use rand::distributions::{Distribution, WeightedIndex};
+use std::collections::BTreeMap;
+
+
+pub trait Scheduler {
+    /// How the scheduler chooses, for `ppppl run` to print.
+    fn describe(&self) -> &'static str;
+
+    /// Pick one of the `eligible` (block index, binding) pairs, which is never
+    /// empty and is in program order, and return its index.
+    fn pick(&mut self, m: &Module<SourceSpan>, eligible: &[(usize, BoundNames)], rng: &mut dyn RngCore) -> usize;
+}
+
+pub const POLICIES: &[&str] = &["random", "round-robin", "weakly-fair", "first-eligible"];
+
+/// The scheduler for a `--policy` name (see `POLICIES`).
+pub fn by_name(name: &str) -> Option<Box<dyn Scheduler>> {
+    match name {
+        "random" => Some(Box::new(Random { })),
+        "round-robin" => Some(Box::new(RoundRobin { last: None })),
+        "weakly-fair" => Some(Box::new(WeaklyFair { steps: 0, enabled_since: BTreeMap::new() })),
+        "first-eligible" => Some(Box::new(FirstEligible { })),
+        _ => None,
+    }
+}
+
+/// The block's `@weight`, in millionths.
+fn weight<A>(b: &Block<A>) -> u64 {
+    for attr in &b.attributes {
+        if let Attribute::Weight(_, w) = attr {
+            return *w;
+        }
+    }
+    return 1_000_000;
+}
+
+/// Uniformly random over instances, scaled by `@weight`.
+pub struct Random {
+}
+
+impl Scheduler for Random {
+    fn describe(&self) -> &'static str {
+        "random, weighted by @weight"
+    }
+
+    fn pick(&mut self, m: &Module<SourceSpan>, eligible: &[(usize, BoundNames)], rng: &mut dyn RngCore) -> usize {
+        let weights: Vec<u64> = eligible.iter().map(|(i, _)| weight(&m.blocks[*i])).collect();
+        match WeightedIndex::new(&weights) {
+            Ok(dist) => dist.sample(rng),
+            Err(_) => rng.gen_range(0..eligible.len()),
+        }
+    }
+}
+
+/// Cycles through the blocks in program order, skipping blocks with no
+/// eligible instances.  The binding is picked at random.
+pub struct RoundRobin {
+    // name of the block that ran last; names (unlike indexes) survive
+    // reloading the program
+    last: Option<String>,
+}
+
+impl Scheduler for RoundRobin {
+    fn describe(&self) -> &'static str {
+        "round-robin over blocks"
+    }
+
+    fn pick(&mut self, m: &Module<SourceSpan>, eligible: &[(usize, BoundNames)], rng: &mut dyn RngCore) -> usize {
+        let last = match &self.last {
+            Some(name) => m.blocks.iter().position(|b| &b.name == name),
+            None => None,
+        };
+        let next_block = match last {
+            Some(last) => eligible.iter().map(|(i, _)| *i).find(|i| *i > last).unwrap_or(eligible[0].0),
+            None => eligible[0].0,
+        };
+        let bindings: Vec<usize> = (0 .. eligible.len()).filter(|j| eligible[*j].0 == next_block).collect();
+        self.last = Some(m.blocks[next_block].name.clone());
+        return bindings[rng.gen_range(0..bindings.len())];
+    }
+}
+
+/// Runs the instance that has been continuously eligible for the longest, so
+/// every instance that stays eligible eventually runs.
+pub struct WeaklyFair {
+    steps: u64,
+
+    // (block name, binding) -> the step since which it has been eligible
+    enabled_since: BTreeMap<(String, BoundNames), u64>,
+}
+
+impl Scheduler for WeaklyFair {
+    fn describe(&self) -> &'static str {
+        "weakly fair (the instance that has been eligible longest runs first)"
+    }
+
+    fn pick(&mut self, m: &Module<SourceSpan>, eligible: &[(usize, BoundNames)], rng: &mut dyn RngCore) -> usize {
+        self.steps += 1;
+
+        // Instances that are no longer eligible lose their place in line.
+        let mut enabled_since = BTreeMap::new();
+        for (i, names) in eligible {
+            let key = (m.blocks[*i].name.clone(), names.clone());
+            let since = self.enabled_since.get(&key).cloned().unwrap_or(self.steps);
+            enabled_since.insert(key, since);
+        }
+        self.enabled_since = enabled_since;
+
+        let since = |j: usize| self.enabled_since[&(m.blocks[eligible[j].0].name.clone(), eligible[j].1.clone())];
+        let oldest = (0 .. eligible.len()).map(since).min().unwrap_or(self.steps);
+        let candidates: Vec<usize> = (0 .. eligible.len()).filter(|j| since(*j) == oldest).collect();
+        let choice = candidates[rng.gen_range(0..candidates.len())];
+
+        // NOTE: if the instance is still eligible after it runs, it goes to
+        // the back of the line.
+        self.enabled_since.remove(&(m.blocks[eligible[choice].0].name.clone(), eligible[choice].1.clone()));
+        return choice;
+    }
+}
+
+/// Always the first eligible instance in program order.  Deterministic, but
+/// not fair at all.
+pub struct FirstEligible {
+}
+
+impl Scheduler for FirstEligible {
+    fn describe(&self) -> &'static str {
+        "first eligible block in program order"
+    }
+
+    fn pick(&mut self, _m: &Module<SourceSpan>, _eligible: &[(usize, BoundNames)], _rng: &mut dyn RngCore) -> usize {
+        0
+    }
+}
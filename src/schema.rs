@@ -0,0 +1,122 @@
+// Runtime checking of pmemory against the program's `schema` declarations.
+
+use crate::syntax::{Type, Value, str2blob};
+
+use std::collections::BTreeMap;
+
+
+/// Render a path the way it would be written in PPPL, e.g. `.["m"]["a"]`.
+pub fn describe_path(path: &[Value]) -> String {
+    let mut res = ".".to_string();
+    for key in path {
+        res.push_str(&format!("[{}]", key));
+    }
+    return res;
+}
+
+fn is_field(fields: &BTreeMap<String, Type>, k: &Value) -> bool {
+    match k {
+        Value::Blob(bytes) => match std::str::from_utf8(bytes) {
+            Ok(name) => fields.contains_key(name),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+fn check(v: &Value, t: &Type, path: &mut Vec<Value>) -> Result<(), String> {
+    let mismatch = |path: &Vec<Value>| Err(format!("{}: expected {}, found {}", describe_path(path), t, v));
+    match (t, v) {
+        (Type::Any, _) => Ok(()),
+        (Type::Int, Value::Int(_)) => Ok(()),
+        (Type::Bool, Value::Bool(_)) => Ok(()),
+        (Type::Blob, Value::Blob(_)) => Ok(()),
+        (Type::Dict(kt, vt), Value::Dict(mapping)) => {
+            for (k, entry) in mapping.iter() {
+                path.push(k.clone());
+                if let Err(e) = check(k, kt, path) {
+                    return Err(format!("bad key; {}", e));
+                }
+                check(entry, vt, path)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        (Type::Record(fields), Value::Dict(mapping)) => {
+            for (name, ft) in fields.iter() {
+                let key = Value::Blob(str2blob(name));
+                path.push(key.clone());
+                match mapping.get(&key) {
+                    Some(entry) => { check(entry, ft, path)?; }
+                    None => { return Err(format!("{}: missing field of type {}", describe_path(path), ft)); }
+                }
+                path.pop();
+            }
+            for k in mapping.keys() {
+                if !is_field(fields, k) {
+                    path.push(k.clone());
+                    return Err(format!("{}: not a field of {}", describe_path(path), t));
+                }
+            }
+            Ok(())
+        }
+        _ => mismatch(path),
+    }
+}
+
+/// Check the whole pmemory value against a schema.  Keys the schema doesn't
+/// mention, and declared keys that don't exist (yet), are allowed.  The error
+/// names the first offending path.
+pub fn validate(root: &Value, schema: &BTreeMap<String, Type>) -> Result<(), String> {
+    let mapping = match root {
+        Value::Dict(mapping) => mapping,
+        _ if schema.is_empty() => { return Ok(()); }
+        _ => { return Err(format!(".: expected a dict, found {}", root)); }
+    };
+    for (key, t) in schema.iter() {
+        let key = Value::Blob(str2blob(key));
+        if let Some(v) = mapping.get(&key) {
+            check(v, t, &mut vec![key])?;
+        }
+    }
+    return Ok(());
+}
+
+/// The types that the values along `path` must have, one for each non-empty
+/// prefix of `path`.  Stops early where the schema stops constraining things
+/// (`any`, or a top-level key it doesn't declare).  Fails if one of the keys
+/// in `path` can't appear there at all.
+pub fn types_along<'a>(schema: &'a BTreeMap<String, Type>, path: &[Value]) -> Result<Vec<&'a Type>, String> {
+    let mut res = Vec::new();
+    let mut t = match path.first() {
+        Some(Value::Blob(bytes)) => match std::str::from_utf8(bytes).ok().and_then(|name| schema.get(name)) {
+            Some(t) => t,
+            None => { return Ok(res); }
+        },
+        _ => { return Ok(res); }
+    };
+    res.push(t);
+    for i in 1 .. path.len() {
+        t = match t {
+            Type::Dict(kt, vt) => {
+                if let Err(e) = check(&path[i], kt, &mut path[..i+1].to_vec()) {
+                    return Err(format!("bad key; {}", e));
+                }
+                vt
+            }
+            Type::Record(fields) => match &path[i] {
+                Value::Blob(bytes) if is_field(fields, &path[i]) => &fields[std::str::from_utf8(bytes).unwrap()],
+                _ => { return Err(format!("{}: not a field of {}", describe_path(&path[..i+1]), t)); }
+            },
+            Type::Any => { return Ok(res); }
+            _ => { return Err(format!("{}: expected {}, found a dict", describe_path(&path[..i]), t)); }
+        };
+        res.push(t);
+    }
+    return Ok(res);
+}
+
+/// Check the value at `path` against its type (see `types_along`).
+pub fn check_at(v: &Value, t: &Type, path: &[Value]) -> Result<(), String> {
+    return check(v, t, &mut path.to_vec());
+}
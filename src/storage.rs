@@ -1,34 +1,77 @@
-// Durable storage stuff.
-
-use crate::syntax::{SourceSpan,Module,Value};
+// Storage stuff: where pmemory lives, and transactions on it.
+//
+// The evaluator only talks to pmemory through the `Backend` and `Transaction`
+// traits.  `SqliteBackend` keeps it in a database file, `LogBackend` in an
+// append-only log file, and `MemoryBackend` in this process, for simulations
+// and experiments that shouldn't touch the disk.
+
+mod sqlite_backend;
+mod memory_backend;
+mod log_backend;
+
+pub use self::sqlite_backend::SqliteBackend;
+pub use self::memory_backend::MemoryBackend;
+pub use self::log_backend::LogBackend;
+
+use crate::syntax::{SourceSpan,Module,Type,Value,str2blob};
 use crate::parse::ModuleParser;
+use crate::schema;
 
 use lalrpop_util::ParseError;
+use serde::{Serialize,Deserialize};
 use sqlite;
 use home;
 use std::fmt::Debug;
-use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use im::OrdMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use bincode;
+use num_bigint::BigInt;
 
 
 #[derive(Debug)]
 pub enum StorageError {
     UnderlyingError(sqlite::Error),
+    FileError(io::Error),
+    NotALogFile(PathBuf),
+    /// A commit or snapshot too big for one record of a log file.
+    RecordTooLarge(usize),
     UnknownSchemaVersion(i64),
     NoHomeDirectory,
+    BadSpaceName(String),
+    CannotCreateDirectory(PathBuf, String),
     SourceCodeIsCorrupt(String),
     MemoryIsCorrupt(String),
+    SchemaViolation(String),
+    /// There's no record of this version of pmemory.
+    NoHistory(i64),
+    /// The backend keeps history whether it's asked to or not.
+    HistoryIsAlwaysOn,
+    /// An optimistic transaction read something that another transaction
+    /// changed before it could commit.  Nothing was written; start over.
+    Conflict,
 }
 
 impl From<sqlite::Error> for StorageError {
     fn from(err: sqlite::Error) -> StorageError {
-        if err.code == Some(SQLITE_BUSY) {
+        if err.code == Some(sqlite_backend::SQLITE_BUSY) {
             panic!("err={:?}", err);
         }
         StorageError::UnderlyingError(err)
     }
 }
 
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> StorageError {
+        StorageError::FileError(err)
+    }
+}
+
 impl <A: Debug, B: Debug, C: Debug> From<ParseError<A, B, C>> for StorageError {
     fn from(err: ParseError<A, B, C>) -> StorageError {
         StorageError::SourceCodeIsCorrupt(format!("{:?}", err))
@@ -41,177 +84,337 @@ impl From<Box<bincode::ErrorKind>> for StorageError {
     }
 }
 
-pub struct Storage {
-    // NOTE: SQLite connections can't be used by multiple threads.  So, we'll
-    // open a new connection for each transaction.
+/// What a commit was for.
+#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize)]
+pub enum Cause {
+    /// a block ran, with these values for its parameters
+    Block(String, BTreeMap<String, Value>),
+    /// `ppppl write`, with the assignment
+    Write(String),
+    /// `ppppl load`, with the file name
+    Load(String),
+    /// `ppppl rollback` or `ppppl undo`, with the version restored
+    Rollback(i64),
 }
 
-pub struct Transaction {
-    conn: sqlite::Connection,
-    mem: Value,
-    mem_changed: bool,
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cause::Block(name, args) if args.is_empty() => write!(f, "`{}`", name),
+            Cause::Block(name, args) => write!(f, "`{}` with arguments {:?}", name, args),
+            Cause::Write(assignment) => write!(f, "write `{}`", assignment),
+            Cause::Load(filename) => write!(f, "load {}", filename),
+            Cause::Rollback(version) => write!(f, "rollback to version {}", version),
+        }
+    }
 }
 
-impl Storage {
+/// A commit, as recorded in pmemory's history.
+pub struct Commit {
+    /// the version of pmemory it made
+    pub version: i64,
+    pub now: BigInt,
+    pub cause: Option<Cause>,
+    /// the new program, if it loaded one
+    pub code: Option<String>,
+    /// what it wrote, in order
+    pub writes: Vec<(Vec<Value>, Value)>,
+}
 
-    pub fn open() -> Result<Storage, StorageError> {
-        let mut s = Storage { };
-        let tx = s.start_transaction()?;
-        tx.conn.execute("CREATE TABLE IF NOT EXISTS clocks(name TEXT PRIMARY KEY, value INT) WITHOUT ROWID;")?;
+/// Somewhere pmemory lives.  Clones refer to the same pmemory, and can be
+/// handed to other threads.
+pub trait Backend: Clone + Send + 'static {
+    type Tx: Transaction + 'static;
+    type Watcher: Watcher;
 
-        let schema_name = "schema_version";
-        let mut stm = tx.conn.prepare("SELECT value FROM clocks WHERE name=?;")?;
-        stm.bind(1, schema_name)?;
-        let mut schema_version = 0;
-        while let sqlite::State::Row = stm.next()? {
-            schema_version = stm.read::<i64>(0)?;
-        }
+    /// Start a transaction that holds the write lock until it commits (or is
+    /// dropped).
+    fn start_transaction(&mut self) -> Result<Self::Tx, StorageError>;
 
-        loop {
-            match schema_version {
-                0 => {
-                    println!("initializing db to v{}", schema_version+1);
-                    tx.conn.execute("CREATE TABLE code (source_code TEXT);")?;
-                    tx.conn.execute("CREATE TABLE mem (bytes BLOB);")?;
-                    stm = tx.conn.prepare("INSERT INTO CLOCKS (name, value) VALUES (?, ?);")?;
-                    stm.bind(1, schema_name)?;
-                    stm.bind(2, schema_version + 1)?;
-                    while stm.next()? != sqlite::State::Done { }
-                    schema_version += 1;
-                }
-                1 => {
-                    // current version; no change needed
-                    break;
-                }
-                _ => {
-                    return Err(StorageError::UnknownSchemaVersion(schema_version));
-                }
-            }
-        }
+    /// Start a transaction that works on a snapshot of pmemory and doesn't
+    /// take the write lock until it commits.  It remembers the paths it reads,
+    /// and `commit` fails with `StorageError::Conflict` if another transaction
+    /// wrote to any of them (or to a path above or below one of them) in the
+    /// meantime.
+    fn start_optimistic(&mut self) -> Result<Self::Tx, StorageError>;
 
-        drop(stm);
-        tx.commit()?;
-        return Ok(s);
-    }
+    fn watch(&mut self) -> Result<Self::Watcher, StorageError>;
+}
 
-    pub fn start_transaction(&mut self) -> Result<Transaction, StorageError> {
-        match home::home_dir() {
-            Some(dir) => Ok(Transaction::new(sqlite::open(dir.join(".pppl.db"))?)?),
-            None => Err(StorageError::NoHomeDirectory),
-        }
-    }
+/// A transaction on pmemory: its program and its value.  Nothing it writes is
+/// visible to anyone else until `commit`.
+pub trait Transaction {
+    /// The transaction's timestamp, in milliseconds since the Unix epoch.  It
+    /// is the same everywhere in the transaction, and never runs backwards
+    /// relative to transactions that committed before this one started.
+    fn now(&self) -> &BigInt;
 
+    fn read_code(&self) -> Result<Arc<Module<SourceSpan>>, StorageError>;
+
+    fn replace_code(&mut self, new_code: &str) -> Result<(), StorageError>;
+
+    /// What's at `path`, including this transaction's own writes.
+    fn read_memory(&self, path: &Vec<Value>) -> Result<Option<Value>, StorageError>;
+
+    /// Whether anything is stored at `path`, without loading it.
+    fn exists(&self, path: &Vec<Value>) -> Result<bool, StorageError>;
+
+    /// Forget the paths read so far, so that they aren't checked for
+    /// conflicts.  For reads that didn't influence the transaction's writes.
+    fn forget_reads(&self);
+
+    /// Store `new_value` at `path`, creating empty dicts along the way.
+    /// Returns false (and does nothing) if the path runs through something
+    /// that isn't a dict.
+    fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> Result<bool, StorageError>;
+
+    /// Say what the transaction is for.  Backends that keep a record of
+    /// commits store this along with it.
+    fn set_cause(&mut self, cause: Cause);
+
+    /// Check what the transaction wrote against the program's schema, and
+    /// make it permanent.
+    fn commit(self) -> Result<(), StorageError> where Self: Sized;
 }
 
-const SQLITE_BUSY: isize = 5; // https://sqlite.org/rescode.html#busy
+/// A backend that can keep every version of pmemory.  Versions count commits
+/// that changed anything (the program or the value), starting from 0 for
+/// empty pmemory.
+pub trait History: Backend {
+    fn history_enabled(&mut self) -> Result<bool, StorageError>;
 
-fn exec_sqlite_until_not_busy<T>(op: T) -> Result<(), sqlite::Error> where T: Fn() -> Result<(), sqlite::Error> {
-    loop {
-        match op() {
-            Ok(x) => { return Ok(x); }
-            Err(e) if e.code == Some(SQLITE_BUSY) => { continue; }
-            Err(e) => { return Err(e); }
-        }
-    }
+    /// The version as of the last commit.
+    fn current_version(&mut self) -> Result<i64, StorageError>;
+
+    /// Start or stop recording history.  Stopping forgets what was recorded.
+    fn set_history(&mut self, on: bool) -> Result<(), StorageError>;
+
+    /// The recorded commits after version `since`, oldest first.
+    fn commits(&mut self, since: i64) -> Result<Vec<Commit>, StorageError>;
+
+    /// Pmemory's program and value as of `version`.
+    fn state_at(&mut self, version: i64) -> Result<(Option<String>, Value), StorageError>;
 }
 
-impl Transaction {
+/// Notices commits, so that idle runners can sleep instead of polling memory.
+pub trait Watcher {
+    /// A number that changes whenever someone else commits.
+    fn version(&self) -> Result<i64, StorageError>;
 
-    fn new(conn: sqlite::Connection) -> Result<Self, StorageError> {
-        exec_sqlite_until_not_busy(|| conn.execute("BEGIN IMMEDIATE;"))?;
+    /// A number that changes whenever a new program is loaded.
+    fn code_version(&self) -> Result<i64, StorageError>;
 
-        let mut stm = conn.prepare("SELECT bytes FROM mem;")?;
-        let mut root = Value::Dict(BTreeMap::new());
-        while let sqlite::State::Row = stm.next()? {
-            let source = stm.read::<Vec<u8>>(0)?;
-            let val = bincode::deserialize::<Value>(&source)?;
-            root = val;
-        }
-        drop(stm);
+    /// Sleep until `version()` differs from `since` or `deadline` passes.
+    /// Returns true if something changed.
+    fn wait_for_change(&self, since: i64, deadline: Option<Instant>) -> Result<bool, StorageError>;
+}
+
+/// Where pmemory lives unless told otherwise: `$PPPL_DB` if it's set, and
+/// `~/.pppl.EXTENSION` if not.
+pub fn default_location(extension: &str) -> Result<PathBuf, StorageError> {
+    if let Some(path) = std::env::var_os("PPPL_DB") {
+        return Ok(PathBuf::from(path));
+    }
+    match home::home_dir() {
+        Some(dir) => Ok(dir.join(format!(".pppl.{}", extension))),
+        None => Err(StorageError::NoHomeDirectory),
+    }
+}
 
-        return Ok(Transaction {
-            conn: conn,
-            mem: root,
-            mem_changed: false,
-        });
+/// Where the named pmemory `name` lives: `~/.pppl/NAME.EXTENSION`.
+pub fn space_location(name: &str, extension: &str) -> Result<PathBuf, StorageError> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(StorageError::BadSpaceName(name.to_string()));
+    }
+    let dir = match home::home_dir() {
+        Some(dir) => dir.join(".pppl"),
+        None => { return Err(StorageError::NoHomeDirectory); }
+    };
+    if let Err(e) = fs::create_dir_all(&dir) {
+        return Err(StorageError::CannotCreateDirectory(dir, e.to_string()));
     }
+    return Ok(dir.join(format!("{}.{}", name, extension)));
+}
 
-    pub fn read_code(&self) -> Result<Module<SourceSpan>, StorageError> {
-        let mut stm = self.conn.prepare("SELECT source_code FROM code;")?;
-        while let sqlite::State::Row = stm.next()? {
-            let source = stm.read::<String>(0)?;
-            let module = ModuleParser::new().parse(&source)?;
-            return Ok(module);
+// The most recently parsed program and its code version.  Shared by every
+// transaction from the same backend, and by its clones in other threads, so
+// that runners don't reparse the program on every step.
+type ProgramCache = Arc<Mutex<Option<(i64, Arc<Module<SourceSpan>>)>>>;
+
+// The program as of `code_version`, parsing `source()` if it isn't cached.
+// `code_changed` means the transaction replaced the program itself.
+fn cached_program<F>(programs: &ProgramCache, code_version: i64, code_changed: bool, source: F) -> Result<Arc<Module<SourceSpan>>, StorageError>
+        where F: FnOnce() -> Result<Option<String>, StorageError> {
+    let mut cache = programs.lock().unwrap();
+    if let Some((version, module)) = &*cache {
+        if *version == code_version && !code_changed {
+            return Ok(module.clone());
         }
+    }
 
-        return Ok(Module {
-            annotation: SourceSpan { start: 0, end: 0 },
-            blocks: Vec::new(),
-        });
+    if let Some(source) = source()? {
+        let module = Arc::new(ModuleParser::new().parse(&source)?);
+        // NOTE: a program that hasn't been committed yet might never be, so
+        // it isn't cached.
+        if !code_changed {
+            *cache = Some((code_version, module.clone()));
+        }
+        return Ok(module);
     }
 
-    pub fn replace_code(&mut self, new_code: &str) -> Result<(), StorageError> {
-        self.conn.execute("DELETE FROM code;")?;
-        let mut stm = self.conn.prepare("INSERT INTO code (source_code) VALUES (?);")?;
-        stm.bind(1, new_code)?;
-        while stm.next()? != sqlite::State::Done { }
-        return Ok(());
+    return Ok(Arc::new(Module {
+        annotation: SourceSpan { start: 0, end: 0 },
+        imports: Vec::new(),
+        blocks: Vec::new(),
+        init: Vec::new(),
+        schema: BTreeMap::new(),
+        constants: BTreeMap::new(),
+    }));
+}
+
+// `Watcher::wait_for_change`, for backends that can only poll `version`.
+fn poll_for_change<F>(version: F, since: i64, deadline: Option<Instant>) -> Result<bool, StorageError>
+        where F: Fn() -> Result<i64, StorageError> {
+    // NOTE: polling starts fast, so that a runner reacts quickly to a
+    // `ppppl write`, and backs off so that an idle runner costs (almost)
+    // nothing.
+    let mut delay = Duration::from_millis(1);
+    loop {
+        if version()? != since {
+            return Ok(true);
+        }
+        let mut sleep = delay;
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            sleep = sleep.min(deadline - now);
+        }
+        thread::sleep(sleep);
+        delay = (delay * 2).min(Duration::from_millis(50));
+    }
+}
+
+// A timestamp for a transaction that is just starting.
+fn timestamp() -> BigInt {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => BigInt::from(elapsed.as_millis()),
+        Err(_) => BigInt::from(0),
     }
+}
+
+/// True if one of the paths is a prefix of the other, i.e. writing to one
+/// can change what's at the other.
+fn overlaps(p1: &[Value], p2: &[Value]) -> bool {
+    p1.iter().zip(p2).all(|(k1, k2)| k1 == k2)
+}
 
-    pub fn read_memory(&self, path: &Vec<Value>) -> Result<Option<&Value>, StorageError> {
-        let mut root = &self.mem;
+/// What's at `path` inside `root`, if anything.
+fn lookup<'a>(root: &'a Value, path: &[Value]) -> Option<&'a Value> {
+    let mut v = root;
+    for entry in path {
+        match v {
+            Value::Dict(mapping) => match mapping.get(entry) {
+                Some(e) => { v = e; }
+                None => { return None; }
+            },
+            _ => { return None; }
+        }
+    }
+    return Some(v);
+}
 
-        for entry in path {
-            match root {
-                Value::Dict(mapping) => {
-                    match mapping.get(entry) {
-                        Option::Some(e) => { root = e; }
-                        None => { return Ok(None); }
-                    }
+/// Store `new_value` at `path`, creating empty dicts along the way.  Returns
+/// false (and does nothing) if the path runs through something that isn't a
+/// dict.
+fn write_path(root: &mut Value, path: &[Value], new_value: &Value) -> bool {
+    let mut env = root;
+    for entry in path {
+        match env {
+            Value::Dict(mapping) => {
+                if !mapping.contains_key(entry) {
+                    mapping.insert(entry.clone(), Value::Dict(OrdMap::new()));
                 }
-                _ => {
-                    return Ok(None);
+                match mapping.get_mut(entry) {
+                    Option::Some(e) => { env = e; }
+                    _ => { return false; /* should be unreachable */ }
                 }
             }
+            _ => {
+                return false;
+            }
         }
+    }
+
+    *env = new_value.clone();
+    return true;
+}
 
-        return Ok(Some(root));
-    }
-
-    pub fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> Result<bool, StorageError> {
-        let mut env = &mut self.mem;
-        for entry in path {
-            match env {
-                Value::Dict(mapping) => {
-                    if !mapping.contains_key(entry) {
-                        mapping.insert(entry.clone(), Value::Dict(BTreeMap::new()));
-                    }
-                    match mapping.get_mut(entry) {
-                        Option::Some(e) => { env = e; }
-                        _ => { return Ok(false); /* should be unreachable */ }
-                    }
+// Check what a commit wrote against the schema, or all of pmemory if the
+// program changed.  `load` reads pmemory with the commit's writes in place.
+fn check_schema<F>(schema: &BTreeMap<String, Type>, written: &BTreeSet<Vec<Value>>, code_changed: bool, root_is_dict: bool, load: F) -> Result<(), StorageError>
+        where F: Fn(&[Value]) -> Result<Option<Value>, StorageError> {
+    if code_changed || written.contains(&Vec::new()) {
+        // NOTE: only the keys the schema declares need to be loaded.
+        let root = if root_is_dict {
+            let mut mapping = OrdMap::new();
+            for name in schema.keys() {
+                let key = Value::Blob(str2blob(name));
+                if let Some(v) = load(&[key.clone()])? {
+                    mapping.insert(key, v);
                 }
-                _ => {
-                    return Ok(false);
+            }
+            Value::Dict(mapping)
+        } else {
+            load(&[])?.unwrap()
+        };
+        return schema::validate(&root, schema).map_err(StorageError::SchemaViolation);
+    }
+    for path in written {
+        let types = schema::types_along(schema, path).map_err(StorageError::SchemaViolation)?;
+        for (i, t) in types.iter().enumerate() {
+            // NOTE: a write can add fields to a record, or create one, so
+            // records along the way are checked in full.
+            let is_record = match t { Type::Record(_) => true, _ => false };
+            if is_record || i + 1 == path.len() {
+                if let Some(v) = load(&path[..i+1])? {
+                    schema::check_at(&v, t, &path[..i+1]).map_err(StorageError::SchemaViolation)?;
                 }
+                break;
             }
         }
-
-        self.mem_changed = true;
-        *env = new_value.clone();
-        return Ok(true);
     }
+    return Ok(());
+}
 
-    pub fn commit(self) -> Result<(), StorageError> {
-        if self.mem_changed {
-            self.conn.execute("DELETE FROM mem;")?;
-            let mut stm = self.conn.prepare("INSERT INTO mem (bytes) VALUES (?);")?;
-            stm.bind(1, &(bincode::serialize(&self.mem)?)[..])?;
-            while stm.next()? != sqlite::State::Done { }
-        }
+// A path for a test's scratch file, which doesn't exist yet.
+#[cfg(test)]
+fn scratch_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ppppl-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    return path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(keys: &[&str]) -> Vec<Value> {
+        keys.iter().map(|k| Value::Blob(str2blob(k))).collect()
+    }
 
-        exec_sqlite_until_not_busy(|| self.conn.execute("COMMIT;"))?;
-        return Ok(());
+    #[test]
+    fn paths_overlap_when_one_is_a_prefix_of_the_other() {
+        assert!(overlaps(&path(&["a"]), &path(&["a"])));
+        assert!(overlaps(&path(&["a"]), &path(&["a", "b"])));
+        assert!(overlaps(&path(&["a", "b"]), &path(&["a"])));
+        assert!(overlaps(&path(&[]), &path(&["a", "b"])));
+        assert!(!overlaps(&path(&["a"]), &path(&["b"])));
+        assert!(!overlaps(&path(&["a", "b"]), &path(&["a", "c"])));
+        assert!(!overlaps(&path(&["a", "b"]), &path(&["b"])));
     }
 
 }
@@ -0,0 +1,610 @@
+// The log backend: pmemory in a single append-only file.
+//
+// The file is a header followed by records.  Each commit appends one record
+// saying what caused it (usually a block running), the new program if there
+// is one, and the paths it wrote along with their new values.  Every so often
+// a snapshot of all of pmemory is appended too, so that opening the file only
+// has to replay the records after the last one.
+//
+// Each process keeps its own copy of pmemory, and catches up on records that
+// other processes appended before it starts a transaction.  Writers hold an
+// exclusive lock on the file, like SQLite's `BEGIN IMMEDIATE`, and catching
+// up takes a shared one, so nobody reads a record that's still being written.
+// A record that a crash left half-written is cut off by the next writer.
+//
+// Since every commit is kept, so is pmemory's whole history.
+
+use super::{Backend, Transaction, Watcher, History, StorageError, Cause, Commit, ProgramCache, poll_for_change};
+use super::memory_backend::{Image, View, corrupt_commit};
+use crate::syntax::{SourceSpan,Module,Value};
+
+use serde::{Serialize,Deserialize};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use bincode;
+use crc32fast;
+use fs2::FileExt;
+use num_bigint::BigInt;
+
+
+const MAGIC: &[u8] = b"PPPLLOG1";
+
+// Record kinds.
+const COMMIT: u8 = 1;
+const SNAPSHOT: u8 = 2;
+
+// A record is its kind, the length of its payload, and the payload's CRC-32
+// (both little-endian), followed by the payload.
+const RECORD_HEADER_LEN: u64 = 9;
+
+// How many commits go by between snapshots.
+const SNAPSHOT_EVERY: u64 = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct CommitRecord {
+    // the memory version after this commit
+    version: i64,
+    now: BigInt,
+    cause: Option<Cause>,
+    code: Option<String>,
+    writes: Vec<(Vec<Value>, Value)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotRecord {
+    version: i64,
+    code_version: i64,
+    code: Option<String>,
+    mem: Value,
+}
+
+// This process's copy of pmemory, and how much of the file it accounts for.
+struct Cache {
+    image: Image,
+    offset: u64,
+    since_snapshot: u64,
+}
+
+#[derive(Clone)]
+pub struct LogBackend {
+    location: PathBuf,
+    cache: Arc<Mutex<Cache>>,
+    programs: ProgramCache,
+}
+
+pub struct LogTransaction {
+    backend: LogBackend,
+
+    // NOTE: a file lock belongs to an open file, not a process, so every
+    // transaction opens the file itself.  A locked transaction holds an
+    // exclusive lock on it until it's dropped.
+    file: File,
+
+    view: View,
+}
+
+pub struct LogWatcher {
+    backend: LogBackend,
+}
+
+impl LogBackend {
+
+    /// Open (creating if need be) the pmemory stored in the log file at
+    /// `location`.
+    pub fn open(location: &Path) -> Result<LogBackend, StorageError> {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(location)?;
+        file.lock_exclusive()?;
+        let mut cache = Cache { image: Image::new(), offset: MAGIC.len() as u64, since_snapshot: 0 };
+
+        let mut magic = Vec::new();
+        (&file).take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+        if !MAGIC.starts_with(&magic) {
+            return Err(StorageError::NotALogFile(location.to_path_buf()));
+        }
+        if magic.len() < MAGIC.len() {
+            // NOTE: a new file, or a header torn by a crash while creating it
+            file.set_len(0)?;
+            (&file).seek(SeekFrom::Start(0))?;
+            (&file).write_all(MAGIC)?;
+            file.sync_all()?;
+        }
+
+        if let Some(offset) = last_snapshot(&file)? {
+            // NOTE: if the snapshot is torn, it's the last record, and
+            // replaying everything before it does just as well.
+            if let Some((_, payload, end)) = read_record(&file, offset)? {
+                let snapshot = bincode::deserialize::<SnapshotRecord>(&payload)?;
+                cache.image.source = snapshot.code;
+                cache.image.code_version = snapshot.code_version;
+                cache.image.version = snapshot.version;
+                cache.image.mem = snapshot.mem;
+                cache.offset = end;
+            }
+        }
+        catch_up(&mut cache, &file, true)?;
+
+        return Ok(LogBackend {
+            location: location.to_path_buf(),
+            cache: Arc::new(Mutex::new(cache)),
+            programs: Arc::new(Mutex::new(None)),
+        });
+    }
+
+    fn open_file(&self) -> Result<File, StorageError> {
+        return Ok(OpenOptions::new().read(true).write(true).open(&self.location)?);
+    }
+
+    // Catch up on other processes' commits, and return the result.
+    fn refresh(&self, file: &File, exclusive: bool) -> Result<MutexGuard<'_, Cache>, StorageError> {
+        // NOTE: the file lock is always taken before the cache's, so that
+        // a transaction holding the first never waits on someone holding
+        // the second.
+        if !exclusive {
+            file.lock_shared()?;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let res = catch_up(&mut cache, file, exclusive);
+        if !exclusive {
+            file.unlock()?;
+        }
+        res?;
+        return Ok(cache);
+    }
+
+    fn start(&mut self, locked: bool) -> Result<LogTransaction, StorageError> {
+        let file = self.open_file()?;
+        if locked {
+            file.lock_exclusive()?;
+        }
+        let view = View::new(&self.refresh(&file, locked)?.image, self.programs.clone(), locked);
+        return Ok(LogTransaction { backend: self.clone(), file: file, view: view });
+    }
+
+}
+
+impl Backend for LogBackend {
+    type Tx = LogTransaction;
+    type Watcher = LogWatcher;
+
+    fn start_transaction(&mut self) -> Result<LogTransaction, StorageError> {
+        return self.start(true);
+    }
+
+    fn start_optimistic(&mut self) -> Result<LogTransaction, StorageError> {
+        return self.start(false);
+    }
+
+    fn watch(&mut self) -> Result<LogWatcher, StorageError> {
+        return Ok(LogWatcher { backend: self.clone() });
+    }
+
+}
+
+impl History for LogBackend {
+
+    fn history_enabled(&mut self) -> Result<bool, StorageError> {
+        return Ok(true);
+    }
+
+    fn current_version(&mut self) -> Result<i64, StorageError> {
+        let file = self.open_file()?;
+        return Ok(self.refresh(&file, false)?.image.version);
+    }
+
+    fn set_history(&mut self, on: bool) -> Result<(), StorageError> {
+        if !on {
+            return Err(StorageError::HistoryIsAlwaysOn);
+        }
+        return Ok(());
+    }
+
+    fn commits(&mut self, since: i64) -> Result<Vec<Commit>, StorageError> {
+        let mut res = Vec::new();
+        scan(&self.open_file()?, |kind, payload| {
+            if kind == COMMIT {
+                let commit = bincode::deserialize::<CommitRecord>(payload)?;
+                if commit.version > since {
+                    res.push(Commit { version: commit.version, now: commit.now, cause: commit.cause, code: commit.code, writes: commit.writes });
+                }
+            }
+            return Ok(());
+        })?;
+        return Ok(res);
+    }
+
+    fn state_at(&mut self, version: i64) -> Result<(Option<String>, Value), StorageError> {
+        // NOTE: replay from the last snapshot at or before `version`.
+        let mut image = Image::new();
+        let mut commits = Vec::new();
+        let mut latest = 0;
+        scan(&self.open_file()?, |kind, payload| {
+            if kind == SNAPSHOT {
+                let snapshot = bincode::deserialize::<SnapshotRecord>(payload)?;
+                latest = snapshot.version;
+                if snapshot.version <= version {
+                    image.source = snapshot.code;
+                    image.code_version = snapshot.code_version;
+                    image.version = snapshot.version;
+                    image.mem = snapshot.mem;
+                    commits.clear();
+                }
+            } else {
+                let commit = bincode::deserialize::<CommitRecord>(payload)?;
+                latest = commit.version;
+                if commit.version > image.version && commit.version <= version {
+                    commits.push(Commit { version: commit.version, now: commit.now, cause: commit.cause, code: commit.code, writes: commit.writes });
+                }
+            }
+            return Ok(());
+        })?;
+        if version < 0 || version > latest {
+            return Err(StorageError::NoHistory(version));
+        }
+        return image.replay(commits, version);
+    }
+
+}
+
+// Call `visit` with the kind and payload of every whole record in the file,
+// in order, holding a shared lock.
+fn scan<F>(file: &File, mut visit: F) -> Result<(), StorageError> where F: FnMut(u8, &[u8]) -> Result<(), StorageError> {
+    file.lock_shared()?;
+    let mut offset = MAGIC.len() as u64;
+    let res = loop {
+        match read_record(file, offset) {
+            Ok(Some((kind, payload, next))) => {
+                if let Err(e) = visit(kind, &payload) {
+                    break Err(e);
+                }
+                offset = next;
+            }
+            Ok(None) => { break Ok(()); }
+            Err(e) => { break Err(e); }
+        }
+    };
+    file.unlock()?;
+    return res;
+}
+
+// Where the last snapshot record starts, if there is one.  Only looks at
+// record headers, and stops at anything that doesn't look like one.
+fn last_snapshot(file: &File) -> Result<Option<u64>, StorageError> {
+    let end = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut offset = MAGIC.len() as u64;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut res = None;
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    while offset + RECORD_HEADER_LEN <= end {
+        reader.read_exact(&mut header)?;
+        let kind = header[0];
+        let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as u64;
+        if (kind != COMMIT && kind != SNAPSHOT) || offset + RECORD_HEADER_LEN + len > end {
+            break;
+        }
+        if kind == SNAPSHOT {
+            res = Some(offset);
+        }
+        reader.seek_relative(len as i64)?;
+        offset += RECORD_HEADER_LEN + len;
+    }
+    return Ok(res);
+}
+
+// The record at `offset`, as (kind, payload, where the next record starts),
+// or None if there isn't a whole one there.
+fn read_record(mut file: &File, offset: u64) -> Result<Option<(u8, Vec<u8>, u64)>, StorageError> {
+    let end = file.metadata()?.len();
+    if offset + RECORD_HEADER_LEN > end {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    let kind = header[0];
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as u64;
+    let crc = u32::from_le_bytes([header[5], header[6], header[7], header[8]]);
+    if (kind != COMMIT && kind != SNAPSHOT) || offset + RECORD_HEADER_LEN + len > end {
+        return Ok(None);
+    }
+    let mut payload = vec![0; len as usize];
+    file.read_exact(&mut payload)?;
+    let next = offset + RECORD_HEADER_LEN + len;
+    if crc32fast::hash(&payload) != crc {
+        // NOTE: only the last record can be torn; anything else is damage.
+        if next < end {
+            return Err(StorageError::MemoryIsCorrupt(format!("bad checksum for the record at offset {}", offset)));
+        }
+        return Ok(None);
+    }
+    return Ok(Some((kind, payload, next)));
+}
+
+// Replay the records after `cache.offset`.  With the exclusive lock held, a
+// torn record at the end is cut off; otherwise it's left for a writer.
+fn catch_up(cache: &mut Cache, file: &File, exclusive: bool) -> Result<(), StorageError> {
+    loop {
+        match read_record(file, cache.offset)? {
+            Some((kind, payload, next)) => {
+                // NOTE: a snapshot is the same as what came before it.
+                if kind == COMMIT {
+                    let commit = bincode::deserialize::<CommitRecord>(&payload)?;
+                    let delta = match cache.image.delta(commit.code, commit.writes) {
+                        Some(delta) => delta,
+                        None => { return Err(corrupt_commit(commit.version)); }
+                    };
+                    cache.image.apply(delta);
+                    cache.image.version = commit.version;
+                    cache.since_snapshot += 1;
+                } else {
+                    cache.since_snapshot = 0;
+                }
+                cache.offset = next;
+            }
+            None => {
+                if exclusive && file.metadata()?.len() > cache.offset {
+                    file.set_len(cache.offset)?;
+                    file.sync_all()?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Append a record, with the exclusive lock held.  Nothing is written if the
+// record is too big.
+fn append<T: Serialize>(cache: &mut Cache, mut file: &File, kind: u8, record: &T) -> Result<(), StorageError> {
+    let payload = bincode::serialize(record)?;
+    let len = match u32::try_from(payload.len()) {
+        Ok(len) => len,
+        Err(_) => { return Err(StorageError::RecordTooLarge(payload.len())); }
+    };
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    bytes.push(kind);
+    bytes.extend(&len.to_le_bytes());
+    bytes.extend(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend(payload);
+    file.seek(SeekFrom::Start(cache.offset))?;
+    file.write_all(&bytes)?;
+    file.sync_data()?;
+    cache.offset += bytes.len() as u64;
+    return Ok(());
+}
+
+impl Watcher for LogWatcher {
+
+    fn version(&self) -> Result<i64, StorageError> {
+        return Ok(self.backend.open_file()?.metadata()?.len() as i64);
+    }
+
+    fn code_version(&self) -> Result<i64, StorageError> {
+        let file = self.backend.open_file()?;
+        return Ok(self.backend.refresh(&file, false)?.image.code_version);
+    }
+
+    fn wait_for_change(&self, since: i64, deadline: Option<Instant>) -> Result<bool, StorageError> {
+        return poll_for_change(|| self.version(), since, deadline);
+    }
+
+}
+
+impl Transaction for LogTransaction {
+
+    fn now(&self) -> &BigInt {
+        &self.view.now
+    }
+
+    fn read_code(&self) -> Result<Arc<Module<SourceSpan>>, StorageError> {
+        return self.view.read_code();
+    }
+
+    fn replace_code(&mut self, new_code: &str) -> Result<(), StorageError> {
+        self.view.replace_code(new_code);
+        return Ok(());
+    }
+
+    fn read_memory(&self, path: &Vec<Value>) -> Result<Option<Value>, StorageError> {
+        return Ok(self.view.read_memory(path));
+    }
+
+    fn exists(&self, path: &Vec<Value>) -> Result<bool, StorageError> {
+        return Ok(self.view.exists(path));
+    }
+
+    fn forget_reads(&self) {
+        self.view.forget_reads();
+    }
+
+    fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> Result<bool, StorageError> {
+        return Ok(self.view.write_memory(path, new_value));
+    }
+
+    fn set_cause(&mut self, cause: Cause) {
+        self.view.cause = Some(cause);
+    }
+
+    fn commit(self) -> Result<(), StorageError> {
+        if !self.view.locked {
+            self.file.lock_exclusive()?;
+        }
+        let mut cache = self.backend.refresh(&self.file, true)?;
+        let delta = cache.image.prepare(&self.view)?;
+        if delta.is_empty() {
+            return Ok(());
+        }
+
+        let record = CommitRecord {
+            version: cache.image.version + 1,
+            now: self.view.now.clone(),
+            cause: self.view.cause.clone(),
+            code: delta.code.clone(),
+            writes: delta.writes.clone(),
+        };
+        append(&mut cache, &self.file, COMMIT, &record)?;
+        cache.image.apply(delta);
+        cache.since_snapshot += 1;
+
+        if cache.since_snapshot >= SNAPSHOT_EVERY {
+            let snapshot = SnapshotRecord {
+                version: cache.image.version,
+                code_version: cache.image.code_version,
+                code: cache.image.source.clone(),
+                mem: cache.image.mem.clone(),
+            };
+            // NOTE: the commit is already in the file, so a snapshot that
+            // doesn't fit in a record is just left out; opening the file
+            // replays from the one before.
+            match append(&mut cache, &self.file, SNAPSHOT, &snapshot) {
+                Ok(()) | Err(StorageError::RecordTooLarge(_)) => { }
+                Err(e) => { return Err(e); }
+            }
+            cache.since_snapshot = 0;
+        }
+        // NOTE: the file lock goes when the file is closed.
+        return Ok(());
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+    use crate::syntax::str2blob;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn path(keys: &[&str]) -> Vec<Value> {
+        keys.iter().map(|k| Value::Blob(str2blob(k))).collect()
+    }
+
+    fn int(i: i64) -> Value {
+        Value::Int(BigInt::from(i))
+    }
+
+    fn write(s: &mut LogBackend, keys: &[&str], i: i64) {
+        let mut tx = s.start_transaction().unwrap();
+        assert!(tx.write_memory(&path(keys), &int(i)).unwrap());
+        tx.commit().unwrap();
+    }
+
+    fn read(s: &mut LogBackend, keys: &[&str]) -> Option<Value> {
+        return s.start_transaction().unwrap().read_memory(&path(keys)).unwrap();
+    }
+
+    fn file_len(location: &Path) -> u64 {
+        return std::fs::metadata(location).unwrap().len();
+    }
+
+    // Flip a bit in the byte at `offset`.
+    fn damage(location: &Path, offset: u64) {
+        let mut file = OpenOptions::new().read(true).write(true).open(location).unwrap();
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 1]).unwrap();
+    }
+
+    #[test]
+    fn a_torn_record_at_the_end_is_cut_off() {
+        let location = scratch_path("log-torn");
+        let mut s = LogBackend::open(&location).unwrap();
+        write(&mut s, &["x"], 1);
+        let whole = file_len(&location);
+        write(&mut s, &["x"], 2);
+        OpenOptions::new().write(true).open(&location).unwrap().set_len(file_len(&location) - 3).unwrap();
+
+        let mut s = LogBackend::open(&location).unwrap();
+        assert_eq!(file_len(&location), whole);
+        assert_eq!(read(&mut s, &["x"]), Some(int(1)));
+        assert_eq!(s.current_version().unwrap(), 1);
+
+        write(&mut s, &["x"], 3);
+        let mut s = LogBackend::open(&location).unwrap();
+        assert_eq!(read(&mut s, &["x"]), Some(int(3)));
+        assert_eq!(s.current_version().unwrap(), 2);
+    }
+
+    #[test]
+    fn a_bad_checksum_at_the_end_is_a_torn_record() {
+        let location = scratch_path("log-torn-crc");
+        let mut s = LogBackend::open(&location).unwrap();
+        write(&mut s, &["x"], 1);
+        let whole = file_len(&location);
+        write(&mut s, &["x"], 2);
+        damage(&location, file_len(&location) - 1);
+
+        let mut s = LogBackend::open(&location).unwrap();
+        assert_eq!(file_len(&location), whole);
+        assert_eq!(read(&mut s, &["x"]), Some(int(1)));
+    }
+
+    #[test]
+    fn a_bad_checksum_before_the_end_is_corruption() {
+        let location = scratch_path("log-corrupt");
+        let mut s = LogBackend::open(&location).unwrap();
+        write(&mut s, &["x"], 1);
+        let first = file_len(&location);
+        write(&mut s, &["x"], 2);
+        damage(&location, first - 1);
+
+        assert!(matches!(LogBackend::open(&location), Err(StorageError::MemoryIsCorrupt(_))));
+        // NOTE: nothing was cut off.
+        assert!(file_len(&location) > first);
+    }
+
+    #[test]
+    fn opening_restores_from_the_last_snapshot() {
+        let location = scratch_path("log-snapshot");
+        let mut s = LogBackend::open(&location).unwrap();
+        write(&mut s, &["first"], 0);
+        let first = file_len(&location);
+        for i in 1 ..= SNAPSHOT_EVERY as i64 {
+            write(&mut s, &["x"], i);
+        }
+        write(&mut s, &["after"], 1);
+        assert!(last_snapshot(&s.open_file().unwrap()).unwrap().is_some());
+
+        // NOTE: the first commit is damaged, but it comes before the
+        // snapshot, so opening never replays it.
+        damage(&location, first - 1);
+        let mut s = LogBackend::open(&location).unwrap();
+        assert_eq!(read(&mut s, &["first"]), Some(int(0)));
+        assert_eq!(read(&mut s, &["x"]), Some(int(SNAPSHOT_EVERY as i64)));
+        assert_eq!(read(&mut s, &["after"]), Some(int(1)));
+        assert_eq!(s.current_version().unwrap(), SNAPSHOT_EVERY as i64 + 2);
+    }
+
+    #[test]
+    fn locked_transactions_exclude_each_other_within_a_process() {
+        let location = scratch_path("log-lock");
+        let mut s = LogBackend::open(&location).unwrap();
+        let mut other = LogBackend::open(&location).unwrap();
+        write(&mut s, &["x"], 1);
+
+        let mut tx = s.start_transaction().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            let mut tx = other.start_transaction().unwrap();
+            let x = tx.read_memory(&path(&["x"])).unwrap();
+            tx.write_memory(&path(&["x"]), &int(3)).unwrap();
+            tx.commit().unwrap();
+            sender.send(x).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+        tx.write_memory(&path(&["x"]), &int(2)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(receiver.recv().unwrap(), Some(int(2)));
+        writer.join().unwrap();
+        assert_eq!(read(&mut s, &["x"]), Some(int(3)));
+    }
+
+}
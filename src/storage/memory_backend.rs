@@ -0,0 +1,572 @@
+// The in-memory backend: pmemory that lives only as long as this process.
+//
+// A transaction starts from a copy of the whole value, which is cheap because
+// dicts are shared until they're changed, and writes straight into it.
+// Committing replays the writes onto whatever is current by then.  The log
+// backend keeps its own copy of pmemory the same way.
+
+use super::{Backend, Transaction, Watcher, StorageError, Cause, Commit, ProgramCache, cached_program, check_schema, overlaps, lookup, write_path, timestamp};
+use crate::syntax::{SourceSpan,Module,Value};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use im::OrdMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+use num_bigint::BigInt;
+
+
+/// Pmemory as of some commit, plus enough history to validate optimistic
+/// transactions that started before it.
+pub(super) struct Image {
+    pub(super) source: Option<String>,
+    pub(super) code_version: i64,
+    pub(super) mem: Value,
+
+    // bumped by every commit that changes pmemory, and the version that last
+    // wrote to each path
+    pub(super) version: i64,
+    versions: BTreeMap<Vec<Value>, i64>,
+}
+
+/// What a commit changes: the new program, if there is one, and the writes
+/// that took effect, along with the value they add up to.
+pub(super) struct Delta {
+    pub(super) code: Option<String>,
+    pub(super) writes: Vec<(Vec<Value>, Value)>,
+    mem: Value,
+}
+
+/// A transaction's view of an `Image`, including its own writes.
+pub(super) struct View {
+    programs: ProgramCache,
+
+    // false for optimistic transactions, which only wait for the write lock
+    // when they commit
+    pub(super) locked: bool,
+
+    // the memory and code versions that the transaction started from
+    version: i64,
+    code_version: i64,
+
+    source: Option<String>,
+    mem: Value,
+
+    // paths read (optimistic transactions only) and written, in order
+    reads: RefCell<BTreeSet<Vec<Value>>>,
+    writes: Vec<(Vec<Value>, Value)>,
+
+    code_changed: bool,
+    pub(super) cause: Option<Cause>,
+    pub(super) now: BigInt,
+}
+
+impl Image {
+
+    pub(super) fn new() -> Image {
+        return Image {
+            source: None,
+            code_version: 0,
+            mem: Value::Dict(OrdMap::new()),
+            version: 0,
+            versions: BTreeMap::new(),
+        };
+    }
+
+    // For optimistic transactions, once the write lock is held: check that no
+    // transaction that committed since the snapshot wrote to anything this
+    // one read, or changed the program.
+    fn validate(&self, view: &View) -> Result<(), StorageError> {
+        if self.code_version != view.code_version {
+            return Err(StorageError::Conflict);
+        }
+        if self.version == view.version {
+            return Ok(());
+        }
+        for read in view.reads.borrow().iter() {
+            // NOTE: everything that overlaps `read` is either one of its
+            // prefixes or sorts right after it.
+            let above = (0 ..= read.len()).filter_map(|i| self.versions.get(&read[..i]));
+            let below = self.versions.range(read.clone() ..).take_while(|(written, _)| overlaps(read, written)).map(|(_, v)| v);
+            if above.chain(below).any(|v| *v > view.version) {
+                return Err(StorageError::Conflict);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Replay `writes` onto the current value, or None if one of them is
+    /// under something that isn't a dict.  Nothing changes until the result
+    /// is `apply`ed.
+    pub(super) fn delta(&self, code: Option<String>, writes: Vec<(Vec<Value>, Value)>) -> Option<Delta> {
+        let mut mem = self.mem.clone();
+        for (path, new_value) in &writes {
+            if !write_path(&mut mem, path, new_value) {
+                return None;
+            }
+        }
+        return Some(Delta { code: code, writes: writes, mem: mem });
+    }
+
+    /// What committing `view` would change, once it's been validated and
+    /// checked against the schema.
+    pub(super) fn prepare(&self, view: &View) -> Result<Delta, StorageError> {
+        if !view.locked {
+            self.validate(view)?;
+        }
+        // NOTE: the writes are replayed, rather than the view's value copied,
+        // so that other transactions' commits since it started are kept.
+        // NOTE: each write fit the view's value, but an optimistic
+        // transaction doesn't count what's above a write as read, so it may
+        // not fit any more.  Dropping it would lose it.
+        let delta = match self.delta(if view.code_changed { view.source.clone() } else { None }, view.writes.clone()) {
+            Some(delta) => delta,
+            None => { return Err(StorageError::Conflict); }
+        };
+        if !delta.is_empty() {
+            let code = view.read_code()?;
+            let root_is_dict = match delta.mem { Value::Dict(_) => true, _ => false };
+            let written = delta.writes.iter().map(|(path, _)| path.clone()).collect();
+            check_schema(&code.schema, &written, view.code_changed, root_is_dict, |path| Ok(lookup(&delta.mem, path).cloned()))?;
+        }
+        return Ok(delta);
+    }
+
+    pub(super) fn apply(&mut self, delta: Delta) {
+        if delta.is_empty() {
+            return;
+        }
+        self.version += 1;
+        if let Some(code) = delta.code {
+            self.source = Some(code);
+            self.code_version += 1;
+        }
+        if !delta.writes.is_empty() {
+            self.mem = delta.mem;
+            for (path, _) in delta.writes {
+                self.versions.insert(path, self.version);
+            }
+        }
+    }
+
+    /// Pmemory's program and value as of `version`, from this image of an
+    /// earlier version and the commits that came after it, in order.
+    pub(super) fn replay(mut self, commits: Vec<Commit>, version: i64) -> Result<(Option<String>, Value), StorageError> {
+        for commit in commits {
+            if commit.version > version {
+                break;
+            }
+            if commit.version != self.version + 1 {
+                return Err(StorageError::NoHistory(self.version + 1));
+            }
+            let delta = match self.delta(commit.code, commit.writes) {
+                Some(delta) => delta,
+                None => { return Err(corrupt_commit(commit.version)); }
+            };
+            self.apply(delta);
+            self.version = commit.version;
+        }
+        if self.version != version {
+            return Err(StorageError::NoHistory(version));
+        }
+        return Ok((self.source, self.mem));
+    }
+
+}
+
+// A recorded commit that writes under something that isn't a dict, which
+// no commit could have done.
+pub(super) fn corrupt_commit(version: i64) -> StorageError {
+    return StorageError::MemoryIsCorrupt(format!("version {} writes under something that isn't a dict", version));
+}
+
+impl Delta {
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.code.is_none() && self.writes.is_empty()
+    }
+
+}
+
+impl View {
+
+    pub(super) fn new(image: &Image, programs: ProgramCache, locked: bool) -> View {
+        return View {
+            programs: programs,
+            locked: locked,
+            version: image.version,
+            code_version: image.code_version,
+            source: image.source.clone(),
+            mem: image.mem.clone(),
+            reads: RefCell::new(BTreeSet::new()),
+            writes: Vec::new(),
+            code_changed: false,
+            cause: None,
+            // NOTE: the caller holds the image's lock; see `Transaction::now`.
+            now: timestamp(),
+        };
+    }
+
+    pub(super) fn read_code(&self) -> Result<Arc<Module<SourceSpan>>, StorageError> {
+        return cached_program(&self.programs, self.code_version, self.code_changed, || Ok(self.source.clone()));
+    }
+
+    pub(super) fn replace_code(&mut self, new_code: &str) {
+        self.source = Some(new_code.to_string());
+        if !self.code_changed {
+            self.code_version += 1;
+        }
+        self.code_changed = true;
+    }
+
+    pub(super) fn read_memory(&self, path: &Vec<Value>) -> Option<Value> {
+        if !self.locked {
+            self.reads.borrow_mut().insert(path.clone());
+        }
+        return lookup(&self.mem, path).cloned();
+    }
+
+    pub(super) fn exists(&self, path: &Vec<Value>) -> bool {
+        if !self.locked {
+            self.reads.borrow_mut().insert(path.clone());
+        }
+        return lookup(&self.mem, path).is_some();
+    }
+
+    pub(super) fn forget_reads(&self) {
+        self.reads.borrow_mut().clear();
+    }
+
+    pub(super) fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> bool {
+        if !write_path(&mut self.mem, path, new_value) {
+            return false;
+        }
+        self.writes.push((path.clone(), new_value.clone()));
+        return true;
+    }
+
+}
+
+struct State {
+    image: Image,
+
+    // bumped by every commit that changes anything, for watchers
+    commits: i64,
+
+    // whether a locking transaction is open
+    locked: bool,
+}
+
+// NOTE: the condition variable is notified whenever `commits` or `locked`
+// changes.
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+#[derive(Clone)]
+pub struct MemoryBackend {
+    shared: Shared,
+    programs: ProgramCache,
+}
+
+pub struct MemoryTransaction {
+    shared: Shared,
+    view: View,
+}
+
+pub struct MemoryWatcher {
+    shared: Shared,
+}
+
+impl MemoryBackend {
+
+    /// A new, empty pmemory.
+    pub fn new() -> MemoryBackend {
+        return MemoryBackend::with(None, Value::Dict(OrdMap::new()));
+    }
+
+    /// A new pmemory holding `source` and `mem`.
+    pub fn with(source: Option<String>, mem: Value) -> MemoryBackend {
+        let mut image = Image::new();
+        image.source = source;
+        image.mem = mem;
+        let state = State { image: image, commits: 0, locked: false };
+        return MemoryBackend {
+            shared: Arc::new((Mutex::new(state), Condvar::new())),
+            programs: Arc::new(Mutex::new(None)),
+        };
+    }
+
+    fn start(&mut self, locked: bool) -> Result<MemoryTransaction, StorageError> {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        if locked {
+            state = wait_for_lock(state, cvar);
+            state.locked = true;
+        }
+        return Ok(MemoryTransaction {
+            shared: self.shared.clone(),
+            view: View::new(&state.image, self.programs.clone(), locked),
+        });
+    }
+
+}
+
+impl Backend for MemoryBackend {
+    type Tx = MemoryTransaction;
+    type Watcher = MemoryWatcher;
+
+    fn start_transaction(&mut self) -> Result<MemoryTransaction, StorageError> {
+        return self.start(true);
+    }
+
+    fn start_optimistic(&mut self) -> Result<MemoryTransaction, StorageError> {
+        return self.start(false);
+    }
+
+    fn watch(&mut self) -> Result<MemoryWatcher, StorageError> {
+        return Ok(MemoryWatcher { shared: self.shared.clone() });
+    }
+
+}
+
+fn wait_for_lock<'a>(mut state: MutexGuard<'a, State>, cvar: &Condvar) -> MutexGuard<'a, State> {
+    while state.locked {
+        state = cvar.wait(state).unwrap();
+    }
+    return state;
+}
+
+impl Watcher for MemoryWatcher {
+
+    fn version(&self) -> Result<i64, StorageError> {
+        return Ok(self.shared.0.lock().unwrap().commits);
+    }
+
+    fn code_version(&self) -> Result<i64, StorageError> {
+        return Ok(self.shared.0.lock().unwrap().image.code_version);
+    }
+
+    fn wait_for_change(&self, since: i64, deadline: Option<Instant>) -> Result<bool, StorageError> {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        while state.commits == since {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+                }
+                None => { state = cvar.wait(state).unwrap(); }
+            }
+        }
+        return Ok(true);
+    }
+
+}
+
+impl Transaction for MemoryTransaction {
+
+    fn now(&self) -> &BigInt {
+        &self.view.now
+    }
+
+    fn read_code(&self) -> Result<Arc<Module<SourceSpan>>, StorageError> {
+        return self.view.read_code();
+    }
+
+    fn replace_code(&mut self, new_code: &str) -> Result<(), StorageError> {
+        self.view.replace_code(new_code);
+        return Ok(());
+    }
+
+    fn read_memory(&self, path: &Vec<Value>) -> Result<Option<Value>, StorageError> {
+        return Ok(self.view.read_memory(path));
+    }
+
+    fn exists(&self, path: &Vec<Value>) -> Result<bool, StorageError> {
+        return Ok(self.view.exists(path));
+    }
+
+    fn forget_reads(&self) {
+        self.view.forget_reads();
+    }
+
+    fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> Result<bool, StorageError> {
+        return Ok(self.view.write_memory(path, new_value));
+    }
+
+    fn set_cause(&mut self, cause: Cause) {
+        self.view.cause = Some(cause);
+    }
+
+    fn commit(self) -> Result<(), StorageError> {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        if !self.view.locked {
+            state = wait_for_lock(state, cvar);
+        }
+        let delta = state.image.prepare(&self.view)?;
+        if !delta.is_empty() {
+            state.image.apply(delta);
+            state.commits += 1;
+            cvar.notify_all();
+        }
+        return Ok(());
+    }
+
+}
+
+impl Drop for MemoryTransaction {
+
+    fn drop(&mut self) {
+        if self.view.locked {
+            let (lock, cvar) = &*self.shared;
+            lock.lock().unwrap().locked = false;
+            cvar.notify_all();
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{apply_init, do_assignment, sim_step, StepOutcome};
+    use crate::parse::ModuleParser;
+    use crate::scheduler;
+    use crate::syntax::str2blob;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn path(keys: &[&str]) -> Vec<Value> {
+        keys.iter().map(|k| Value::Blob(str2blob(k))).collect()
+    }
+
+    fn int(i: i64) -> Value {
+        Value::Int(BigInt::from(i))
+    }
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(entries.into_iter().map(|(k, v)| (Value::Blob(str2blob(k)), v)).collect())
+    }
+
+    // What `ppppl load` does.
+    fn load(s: &mut MemoryBackend, source: &str) {
+        let module = ModuleParser::new().parse(source).unwrap();
+        let mut tx = s.start_transaction().unwrap();
+        tx.replace_code(source).unwrap();
+        apply_init(&module, &mut tx, &mut StdRng::seed_from_u64(0)).unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn write(s: &mut MemoryBackend, keys: &[&str], v: Value) {
+        let mut tx = s.start_transaction().unwrap();
+        assert!(tx.write_memory(&path(keys), &v).unwrap());
+        tx.commit().unwrap();
+    }
+
+    fn read(s: &mut MemoryBackend, keys: &[&str]) -> Option<Value> {
+        return s.start_transaction().unwrap().read_memory(&path(keys)).unwrap();
+    }
+
+    #[test]
+    fn init_only_sets_what_is_absent() {
+        let mut s = MemoryBackend::with(None, dict(vec![("x", int(5))]));
+        load(&mut s, "init {\n    x := 0;\n    y := 1;\n}\n");
+        assert_eq!(read(&mut s, &[]), Some(dict(vec![("x", int(5)), ("y", int(1))])));
+    }
+
+    #[test]
+    fn sim_step_runs_blocks_until_deadlock() {
+        let mut s = MemoryBackend::new();
+        load(&mut s, "init {\n    x := 0;\n}\n\ndef up:\n    require x < 3;\n    x := x + 1;\n");
+        let mut scheduler = scheduler::by_name("first-eligible").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut steps = 0;
+        loop {
+            match sim_step(&mut s, &mut *scheduler, &mut rng).unwrap() {
+                StepOutcome::TriggeredBlock(name, _) => { assert_eq!(name, "up"); steps += 1; }
+                StepOutcome::Deadlock => { break; }
+                StepOutcome::WaitingUntil(_) => panic!("nothing waits on a timer"),
+            }
+        }
+        assert_eq!(steps, 3);
+        assert_eq!(read(&mut s, &["x"]), Some(int(3)));
+    }
+
+    #[test]
+    fn reset_restarts_processes_but_keeps_timers() {
+        let mut s = MemoryBackend::new();
+        load(&mut s, "process p {\n    step a:\n        x := 1;\n}\n");
+        let mut scheduler = scheduler::by_name("first-eligible").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        while let StepOutcome::TriggeredBlock(_, _) = sim_step(&mut s, &mut *scheduler, &mut rng).unwrap() { }
+        assert_eq!(read(&mut s, &["@pc", "p"]), Some(Value::Blob(str2blob("@done"))));
+        write(&mut s, &["@every"], dict(vec![("tick", int(5))]));
+
+        // What `ppppl load --reset` does.
+        let mut tx = s.start_transaction().unwrap();
+        do_assignment(&Vec::new(), &Value::Dict(OrdMap::new()), &mut tx).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(read(&mut s, &[]), Some(dict(vec![("@every", dict(vec![("tick", int(5))]))])));
+
+        match sim_step(&mut s, &mut *scheduler, &mut rng).unwrap() {
+            StepOutcome::TriggeredBlock(name, _) => { assert_eq!(name, "p.a"); }
+            _ => panic!("the process should have started over"),
+        }
+    }
+
+    #[test]
+    fn commits_that_violate_the_schema_are_rolled_back() {
+        let mut s = MemoryBackend::new();
+        load(&mut s, "schema {\n    x: int;\n}\n\ninit {\n    x := 0;\n}\n");
+        let mut tx = s.start_transaction().unwrap();
+        tx.write_memory(&path(&["x"]), &Value::Bool(true)).unwrap();
+        assert!(matches!(tx.commit(), Err(StorageError::SchemaViolation(_))));
+        assert_eq!(read(&mut s, &["x"]), Some(int(0)));
+    }
+
+    // An optimistic transaction that reads `read` and then writes `x := 1`,
+    // with a commit that writes `written` in between.
+    fn race(read: &[&str], written: &[&str]) -> Result<(), StorageError> {
+        let mut s = MemoryBackend::new();
+        let mut tx = s.start_optimistic().unwrap();
+        tx.read_memory(&path(read)).unwrap();
+        write(&mut s, written, int(2));
+        tx.write_memory(&path(&["x"]), &int(1)).unwrap();
+        let res = tx.commit();
+        assert_eq!(read_x(&mut s), if res.is_ok() { Some(int(1)) } else { None });
+        return res;
+    }
+
+    fn read_x(s: &mut MemoryBackend) -> Option<Value> {
+        return read(s, &["x"]);
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_with_overlapping_writes() {
+        assert!(matches!(race(&["a"], &["a"]), Err(StorageError::Conflict)));
+        assert!(matches!(race(&["a"], &["a", "b"]), Err(StorageError::Conflict)));
+        assert!(matches!(race(&["a", "b"], &["a"]), Err(StorageError::Conflict)));
+        assert!(race(&["a", "b"], &["a", "c"]).is_ok());
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_when_a_write_no_longer_fits() {
+        let mut s = MemoryBackend::new();
+        let mut tx = s.start_optimistic().unwrap();
+        write(&mut s, &["a"], int(2));
+        assert!(tx.write_memory(&path(&["a", "b"]), &int(1)).unwrap());
+        assert!(matches!(tx.commit(), Err(StorageError::Conflict)));
+        assert_eq!(read(&mut s, &["a"]), Some(int(2)));
+    }
+
+    #[test]
+    fn replaying_a_write_that_does_not_fit_is_corruption() {
+        let commit = |version, keys: &[&str]| Commit { version: version, now: BigInt::from(0), cause: None, code: None, writes: vec![(path(keys), int(1))] };
+        let res = Image::new().replay(vec![commit(1, &["a"]), commit(2, &["a", "b"])], 2);
+        assert!(matches!(res, Err(StorageError::MemoryIsCorrupt(_))));
+    }
+
+}
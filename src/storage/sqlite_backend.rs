@@ -0,0 +1,924 @@
+// The SQLite backend.
+//
+// Pmemory is stored one row per path in the `entries` table.  A dict is a row
+// with a NULL value plus a row for each of its entries, and anything else is
+// a row holding the bincode-encoded value.  A path is encoded as its encoded
+// keys, one after another, so the rows at and under a path are exactly the
+// ones whose encoding starts with that path's: one range scan.
+//
+// With history on, every commit also adds a row to `history` saying what it
+// wrote, and turning history on takes a full copy of pmemory into `snapshots`
+// to replay those rows from.
+
+use super::{Backend, Transaction, Watcher, History, StorageError, Cause, Commit, ProgramCache, cached_program, check_schema, poll_for_change, overlaps, lookup, write_path, timestamp};
+use super::memory_backend::Image;
+use crate::syntax::{SourceSpan,Module,Value};
+
+use sqlite;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use im::OrdMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use bincode;
+use num_bigint::BigInt;
+
+
+#[derive(Clone)]
+pub struct SqliteBackend {
+    // NOTE: SQLite connections can't be used by multiple threads.  So, we'll
+    // open a new connection for each transaction.
+
+    location: PathBuf,
+    programs: ProgramCache,
+}
+
+pub struct SqliteTransaction {
+    conn: sqlite::Connection,
+    programs: ProgramCache,
+
+    // false for optimistic transactions, which only take the write lock in
+    // `commit`
+    locked: bool,
+
+    // the memory and code versions that the transaction started from
+    version: i64,
+    code_version: i64,
+
+    // values loaded from the database so far (without this transaction's
+    // own writes), by path
+    loaded: RefCell<BTreeMap<Vec<Value>, Option<Value>>>,
+
+    // paths read (optimistic transactions only) and written, in order
+    reads: RefCell<BTreeSet<Vec<Value>>>,
+    writes: Vec<(Vec<Value>, Value)>,
+
+    code_changed: bool,
+    cause: Option<Cause>,
+    now: BigInt,
+}
+
+/// Notices commits made by other connections (including other transactions
+/// in this process), so that idle runners can sleep instead of polling memory.
+pub struct SqliteWatcher {
+    conn: sqlite::Connection,
+}
+
+impl SqliteBackend {
+
+    /// Open (creating if need be) the pmemory stored in the database file at
+    /// `location`.
+    pub fn open(location: &Path) -> Result<SqliteBackend, StorageError> {
+        let s = SqliteBackend { location: location.to_path_buf(), programs: Arc::new(Mutex::new(None)) };
+
+        // NOTE: this can't go through `start_transaction`, which expects the
+        // tables to exist already.
+        let conn = s.connect()?;
+        // NOTE: in WAL mode, reading a snapshot never waits for a commit (or
+        // vice versa), which is what optimistic transactions rely on.  The
+        // setting is stored in the database file.
+        exec_sqlite_until_not_busy(|| conn.execute("PRAGMA journal_mode=WAL;"))?;
+        exec_sqlite_until_not_busy(|| conn.execute("BEGIN IMMEDIATE;"))?;
+        conn.execute("CREATE TABLE IF NOT EXISTS clocks(name TEXT PRIMARY KEY, value INT) WITHOUT ROWID;")?;
+
+        let schema_name = "schema_version";
+        let mut schema_version = read_clock(&conn, schema_name)?.unwrap_or(0);
+        loop {
+            match schema_version {
+                0 => {
+                    println!("initializing db to v{}", schema_version+1);
+                    conn.execute("CREATE TABLE code (source_code TEXT);")?;
+                    conn.execute("CREATE TABLE mem (bytes BLOB);")?;
+                }
+                1 => {
+                    // v2: per-path versions, for optimistic transactions
+                    println!("upgrading db to v{}", schema_version+1);
+                    conn.execute("CREATE TABLE versions(path BLOB PRIMARY KEY, version INT) WITHOUT ROWID;")?;
+                    conn.execute("CREATE INDEX versions_by_version ON versions(version);")?;
+                    set_clock(&conn, MEM_VERSION, 0)?;
+                }
+                2 => {
+                    // v3: one row per path instead of one blob
+                    println!("upgrading db to v{}", schema_version+1);
+                    conn.execute("CREATE TABLE entries(path BLOB PRIMARY KEY, value BLOB) WITHOUT ROWID;")?;
+                    let root = decode_mem(read_mem(&conn)?)?;
+                    let mut stm = conn.prepare("INSERT INTO entries (path, value) VALUES (?, ?);")?;
+                    insert_tree(&mut stm, &mut Vec::new(), &root)?;
+                    drop(stm);
+                    conn.execute("DROP TABLE mem;")?;
+                }
+                3 => {
+                    // v4: a code version, bumped by every `load`, to key the
+                    // program cache
+                    println!("upgrading db to v{}", schema_version+1);
+                    let has_code = read_source(&conn)?.is_some();
+                    set_clock(&conn, CODE_VERSION, if has_code { 1 } else { 0 })?;
+                }
+                4 => {
+                    // v5: optional history
+                    println!("upgrading db to v{}", schema_version+1);
+                    conn.execute("CREATE TABLE history(version INTEGER PRIMARY KEY, now TEXT, cause BLOB, code TEXT, writes BLOB);")?;
+                    conn.execute("CREATE TABLE snapshots(version INTEGER PRIMARY KEY, code TEXT, mem BLOB);")?;
+                    set_clock(&conn, HISTORY, 0)?;
+                }
+                5 => {
+                    // current version; no change needed
+                    break;
+                }
+                _ => {
+                    return Err(StorageError::UnknownSchemaVersion(schema_version));
+                }
+            }
+            schema_version += 1;
+            set_clock(&conn, schema_name, schema_version)?;
+        }
+
+        exec_sqlite_until_not_busy(|| conn.execute("COMMIT;"))?;
+        return Ok(s);
+    }
+
+    fn connect(&self) -> Result<sqlite::Connection, StorageError> {
+        return Ok(sqlite::open(&self.location)?);
+    }
+
+}
+
+impl Backend for SqliteBackend {
+    type Tx = SqliteTransaction;
+    type Watcher = SqliteWatcher;
+
+    fn start_transaction(&mut self) -> Result<SqliteTransaction, StorageError> {
+        return SqliteTransaction::new(self.connect()?, self.programs.clone(), true);
+    }
+
+    fn start_optimistic(&mut self) -> Result<SqliteTransaction, StorageError> {
+        return SqliteTransaction::new(self.connect()?, self.programs.clone(), false);
+    }
+
+    fn watch(&mut self) -> Result<SqliteWatcher, StorageError> {
+        return Ok(SqliteWatcher { conn: self.connect()? });
+    }
+
+}
+
+pub(super) const SQLITE_BUSY: isize = 5; // https://sqlite.org/rescode.html#busy
+
+fn exec_sqlite_until_not_busy<R, T>(mut op: T) -> Result<R, sqlite::Error> where T: FnMut() -> Result<R, sqlite::Error> {
+    loop {
+        match op() {
+            Ok(x) => { return Ok(x); }
+            Err(e) if e.code == Some(SQLITE_BUSY) => { thread::yield_now(); continue; }
+            Err(e) => { return Err(e); }
+        }
+    }
+}
+
+// Bumped by every commit that changes pmemory: its program or its value.
+const MEM_VERSION: &str = "mem_version";
+
+// Bumped by every commit that replaces the program.
+const CODE_VERSION: &str = "code_version";
+
+// 1 if commits are recorded in `history`, 0 if not.
+const HISTORY: &str = "history";
+
+fn read_clock(conn: &sqlite::Connection, name: &str) -> Result<Option<i64>, sqlite::Error> {
+    let mut stm = conn.prepare("SELECT value FROM clocks WHERE name=?;")?;
+    stm.bind(1, name)?;
+    let mut res = None;
+    while let sqlite::State::Row = stm.next()? {
+        res = Some(stm.read::<i64>(0)?);
+    }
+    return Ok(res);
+}
+
+fn set_clock(conn: &sqlite::Connection, name: &str, value: i64) -> Result<(), sqlite::Error> {
+    let mut stm = conn.prepare("INSERT OR REPLACE INTO clocks (name, value) VALUES (?, ?);")?;
+    stm.bind(1, name)?;
+    stm.bind(2, value)?;
+    while stm.next()? != sqlite::State::Done { }
+    return Ok(());
+}
+
+fn read_source(conn: &sqlite::Connection) -> Result<Option<String>, sqlite::Error> {
+    let mut stm = conn.prepare("SELECT source_code FROM code;")?;
+    let mut res = None;
+    while let sqlite::State::Row = stm.next()? {
+        res = Some(stm.read::<String>(0)?);
+    }
+    return Ok(res);
+}
+
+fn read_mem(conn: &sqlite::Connection) -> Result<Option<Vec<u8>>, sqlite::Error> {
+    let mut stm = conn.prepare("SELECT bytes FROM mem;")?;
+    let mut res = None;
+    while let sqlite::State::Row = stm.next()? {
+        res = Some(stm.read::<Vec<u8>>(0)?);
+    }
+    return Ok(res);
+}
+
+fn decode_mem(bytes: Option<Vec<u8>>) -> Result<Value, StorageError> {
+    match bytes {
+        Some(bytes) => Ok(bincode::deserialize::<Value>(&bytes)?),
+        None => Ok(Value::Dict(OrdMap::new())),
+    }
+}
+
+fn encode_path(path: &[Value]) -> Result<Vec<u8>, StorageError> {
+    let mut res = Vec::new();
+    for key in path {
+        res.extend(bincode::serialize(key)?);
+    }
+    return Ok(res);
+}
+
+fn decode_path(mut bytes: &[u8]) -> Result<Vec<Value>, StorageError> {
+    let mut res = Vec::new();
+    while !bytes.is_empty() {
+        res.push(bincode::deserialize_from(&mut bytes)?);
+    }
+    return Ok(res);
+}
+
+// The smallest encoding that sorts after every path under `prefix`, if there
+// is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    return None;
+}
+
+// The rows at and under the encoded path `prefix`, in order, as (encoded
+// path, encoded value or None for a dict).
+fn subtree_rows(conn: &sqlite::Connection, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>, StorageError> {
+    let mut stm = match prefix_end(prefix) {
+        Some(end) => {
+            let mut stm = conn.prepare("SELECT path, value FROM entries WHERE path >= ? AND path < ? ORDER BY path;")?;
+            stm.bind(2, &end[..])?;
+            stm
+        }
+        None => conn.prepare("SELECT path, value FROM entries WHERE path >= ? ORDER BY path;")?,
+    };
+    stm.bind(1, prefix)?;
+    let mut res = Vec::new();
+    while let sqlite::State::Row = stm.next()? {
+        let value = match stm.column_type(1) {
+            sqlite::Type::Null => None,
+            _ => Some(stm.read::<Vec<u8>>(1)?),
+        };
+        res.push((stm.read::<Vec<u8>>(0)?, value));
+    }
+    return Ok(res);
+}
+
+fn load_subtree(conn: &sqlite::Connection, path: &[Value]) -> Result<Option<Value>, StorageError> {
+    let prefix = encode_path(path)?;
+    let rows = subtree_rows(conn, &prefix)?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    let mut res = Value::Dict(OrdMap::new());
+    for (row_path, value) in rows {
+        let value = match value {
+            Some(bytes) => bincode::deserialize::<Value>(&bytes)?,
+            None => Value::Dict(OrdMap::new()),
+        };
+        // NOTE: rows come in path order, so dicts come before their entries.
+        write_path(&mut res, &decode_path(&row_path[prefix.len()..])?, &value);
+    }
+    return Ok(Some(res));
+}
+
+fn delete_subtree(conn: &sqlite::Connection, prefix: &[u8]) -> Result<(), StorageError> {
+    let mut stm = match prefix_end(prefix) {
+        Some(end) => {
+            let mut stm = conn.prepare("DELETE FROM entries WHERE path >= ? AND path < ?;")?;
+            stm.bind(2, &end[..])?;
+            stm
+        }
+        None => conn.prepare("DELETE FROM entries WHERE path >= ?;")?,
+    };
+    stm.bind(1, prefix)?;
+    while stm.next()? != sqlite::State::Done { }
+    return Ok(());
+}
+
+// `stm` inserts a row into `entries`.
+fn insert_tree(stm: &mut sqlite::Statement, path: &mut Vec<u8>, v: &Value) -> Result<(), StorageError> {
+    stm.reset()?;
+    stm.bind(1, &path[..])?;
+    match v {
+        Value::Dict(_) => { stm.bind(2, ())?; }
+        _ => { stm.bind(2, &(bincode::serialize(v)?)[..])?; }
+    }
+    while stm.next()? != sqlite::State::Done { }
+
+    if let Value::Dict(mapping) = v {
+        for (k, entry) in mapping {
+            let len = path.len();
+            path.extend(bincode::serialize(k)?);
+            insert_tree(stm, path, entry)?;
+            path.truncate(len);
+        }
+    }
+    return Ok(());
+}
+
+#[derive(PartialEq)]
+enum Node {
+    Absent,
+    Dict,
+    Other,
+}
+
+fn node_of(v: Option<&Value>) -> Node {
+    match v {
+        None => Node::Absent,
+        Some(Value::Dict(_)) => Node::Dict,
+        Some(_) => Node::Other,
+    }
+}
+
+// What kind of thing is stored at `path`, without loading all of it.
+fn stored_node(conn: &sqlite::Connection, path: &[Value]) -> Result<Node, StorageError> {
+    let mut stm = conn.prepare("SELECT value IS NULL FROM entries WHERE path = ?;")?;
+    stm.bind(1, &encode_path(path)?[..])?;
+    let mut res = Node::Absent;
+    while let sqlite::State::Row = stm.next()? {
+        res = if stm.read::<i64>(0)? != 0 { Node::Dict } else { Node::Other };
+    }
+    return Ok(res);
+}
+
+// `write_path`, for the rows in the database.
+fn store(conn: &sqlite::Connection, path: &[Value], new_value: &Value) -> Result<bool, StorageError> {
+    for i in 0 .. path.len() {
+        match stored_node(conn, &path[..i])? {
+            Node::Dict => { }
+            Node::Absent => {
+                let mut stm = conn.prepare("INSERT INTO entries (path, value) VALUES (?, NULL);")?;
+                stm.bind(1, &encode_path(&path[..i])?[..])?;
+                while stm.next()? != sqlite::State::Done { }
+            }
+            Node::Other => { return Ok(false); }
+        }
+    }
+    let mut prefix = encode_path(path)?;
+    delete_subtree(conn, &prefix)?;
+    let mut stm = conn.prepare("INSERT INTO entries (path, value) VALUES (?, ?);")?;
+    insert_tree(&mut stm, &mut prefix, new_value)?;
+    return Ok(true);
+}
+
+impl Watcher for SqliteWatcher {
+
+    fn version(&self) -> Result<i64, StorageError> {
+        let mut version = 0;
+        exec_sqlite_until_not_busy(|| {
+            let mut stm = self.conn.prepare("PRAGMA data_version;")?;
+            while let sqlite::State::Row = stm.next()? {
+                version = stm.read::<i64>(0)?;
+            }
+            Ok(())
+        })?;
+        return Ok(version);
+    }
+
+    fn code_version(&self) -> Result<i64, StorageError> {
+        return Ok(exec_sqlite_until_not_busy(|| read_clock(&self.conn, CODE_VERSION))?.unwrap_or(0));
+    }
+
+    fn wait_for_change(&self, since: i64, deadline: Option<Instant>) -> Result<bool, StorageError> {
+        return poll_for_change(|| self.version(), since, deadline);
+    }
+
+}
+
+impl SqliteTransaction {
+
+    fn new(conn: sqlite::Connection, programs: ProgramCache, locked: bool) -> Result<Self, StorageError> {
+        // NOTE: an optimistic transaction is a read transaction until it
+        // commits.  In WAL mode, it sees the database as of its first read,
+        // and doesn't hold up anyone else.
+        exec_sqlite_until_not_busy(|| conn.execute(if locked { "BEGIN IMMEDIATE;" } else { "BEGIN;" }))?;
+        let (version, code_version) = exec_sqlite_until_not_busy(|| Ok((read_clock(&conn, MEM_VERSION)?, read_clock(&conn, CODE_VERSION)?)))?;
+
+        return Ok(SqliteTransaction {
+            conn: conn,
+            programs: programs,
+            locked: locked,
+            version: version.unwrap_or(0),
+            code_version: code_version.unwrap_or(0),
+            loaded: RefCell::new(BTreeMap::new()),
+            reads: RefCell::new(BTreeSet::new()),
+            writes: Vec::new(),
+            code_changed: false,
+            cause: None,
+            // NOTE: read after the lock is acquired (or the snapshot is
+            // taken); see `Transaction::now`.
+            now: timestamp(),
+        });
+    }
+
+    // What's at `path` in the database, as of the start of the transaction.
+    fn load(&self, path: &[Value]) -> Result<Option<Value>, StorageError> {
+        let mut loaded = self.loaded.borrow_mut();
+        for i in (0 ..= path.len()).rev() {
+            if let Some(v) = loaded.get(&path[..i]) {
+                return Ok(v.as_ref().and_then(|v| lookup(v, &path[i..])).cloned());
+            }
+        }
+        let mut v = load_subtree(&self.conn, path)?;
+        if path.is_empty() && v.is_none() {
+            v = Some(Value::Dict(OrdMap::new()));
+        }
+        loaded.insert(path.to_vec(), v.clone());
+        return Ok(v);
+    }
+
+    // Like `read_memory`, but only says what kind of thing is at `path`, and
+    // doesn't count as a read.
+    fn node(&self, path: &[Value]) -> Result<Node, StorageError> {
+        let mut res = {
+            let loaded = self.loaded.borrow();
+            match (0 ..= path.len()).rev().find_map(|i| loaded.get(&path[..i]).map(|v| (i, v))) {
+                Some((i, v)) => node_of(v.as_ref().and_then(|v| lookup(v, &path[i..]))),
+                None => stored_node(&self.conn, path)?,
+            }
+        };
+        if path.is_empty() && res == Node::Absent {
+            res = Node::Dict;
+        }
+        for (written, new_value) in &self.writes {
+            if path.starts_with(written) {
+                res = node_of(lookup(new_value, &path[written.len()..]));
+            } else if written.starts_with(path) {
+                res = Node::Dict;
+            }
+        }
+        return Ok(res);
+    }
+
+    // For optimistic transactions, once the write lock is held: check that no
+    // transaction that committed since the snapshot wrote to anything this
+    // one read, or changed the program.
+    fn validate(&self) -> Result<(), StorageError> {
+        if read_clock(&self.conn, CODE_VERSION)?.unwrap_or(0) != self.code_version {
+            return Err(StorageError::Conflict);
+        }
+        if read_clock(&self.conn, MEM_VERSION)?.unwrap_or(0) == self.version {
+            return Ok(());
+        }
+
+        let mut stm = self.conn.prepare("SELECT path FROM versions WHERE version > ?;")?;
+        stm.bind(1, self.version)?;
+        let reads = self.reads.borrow();
+        while let sqlite::State::Row = stm.next()? {
+            let written = bincode::deserialize::<Vec<Value>>(&stm.read::<Vec<u8>>(0)?)?;
+            if reads.iter().any(|read| overlaps(read, &written)) {
+                return Err(StorageError::Conflict);
+            }
+        }
+        return Ok(());
+    }
+
+}
+
+impl Transaction for SqliteTransaction {
+
+    fn now(&self) -> &BigInt {
+        &self.now
+    }
+
+    fn read_code(&self) -> Result<Arc<Module<SourceSpan>>, StorageError> {
+        return cached_program(&self.programs, self.code_version, self.code_changed, || Ok(read_source(&self.conn)?));
+    }
+
+    fn replace_code(&mut self, new_code: &str) -> Result<(), StorageError> {
+        self.conn.execute("DELETE FROM code;")?;
+        let mut stm = self.conn.prepare("INSERT INTO code (source_code) VALUES (?);")?;
+        stm.bind(1, new_code)?;
+        while stm.next()? != sqlite::State::Done { }
+        if !self.code_changed {
+            self.code_version += 1;
+            set_clock(&self.conn, CODE_VERSION, self.code_version)?;
+        }
+        self.code_changed = true;
+        return Ok(());
+    }
+
+    fn read_memory(&self, path: &Vec<Value>) -> Result<Option<Value>, StorageError> {
+        if !self.locked {
+            self.reads.borrow_mut().insert(path.clone());
+        }
+
+        let mut res = self.load(path)?;
+        for (written, new_value) in &self.writes {
+            if path.starts_with(written) {
+                res = lookup(new_value, &path[written.len()..]).cloned();
+            } else if written.starts_with(path) {
+                let mut v = match res {
+                    Some(v @ Value::Dict(_)) => v,
+                    _ => Value::Dict(OrdMap::new()),
+                };
+                write_path(&mut v, &written[path.len()..], new_value);
+                res = Some(v);
+            }
+        }
+        return Ok(res);
+    }
+
+    fn exists(&self, path: &Vec<Value>) -> Result<bool, StorageError> {
+        if !self.locked {
+            self.reads.borrow_mut().insert(path.clone());
+        }
+        return Ok(self.node(path)? != Node::Absent);
+    }
+
+    fn forget_reads(&self) {
+        self.reads.borrow_mut().clear();
+    }
+
+    fn write_memory(&mut self, path: &Vec<Value>, new_value: &Value) -> Result<bool, StorageError> {
+        for i in 0 .. path.len() {
+            if self.node(&path[..i])? == Node::Other {
+                return Ok(false);
+            }
+        }
+        self.writes.push((path.clone(), new_value.clone()));
+        return Ok(true);
+    }
+
+    fn set_cause(&mut self, cause: Cause) {
+        self.cause = Some(cause);
+    }
+
+    fn commit(self) -> Result<(), StorageError> {
+        if !self.locked {
+            // NOTE: a read transaction can't become a write transaction once
+            // someone else has committed, so end it and start over.
+            self.conn.execute("COMMIT;")?;
+            exec_sqlite_until_not_busy(|| self.conn.execute("BEGIN IMMEDIATE;"))?;
+            if let Err(e) = self.validate() {
+                self.conn.execute("ROLLBACK;")?;
+                return Err(e);
+            }
+        }
+
+        let mut written = BTreeSet::new();
+        for (path, new_value) in &self.writes {
+            // NOTE: `write_memory` checked that nothing above `path` is a
+            // non-dict as of this transaction's snapshot, but that isn't
+            // counted as a read, so an optimistic transaction may find one
+            // stored there since.  Dropping the write would lose it.
+            if !store(&self.conn, path, new_value)? {
+                self.conn.execute("ROLLBACK;")?;
+                return Err(StorageError::Conflict);
+            }
+            written.insert(path.clone());
+        }
+
+        if !written.is_empty() || self.code_changed {
+            let code = self.read_code()?;
+            let root_is_dict = stored_node(&self.conn, &[])? != Node::Other;
+            if let Err(e) = check_schema(&code.schema, &written, self.code_changed, root_is_dict, |path| load_subtree(&self.conn, path)) {
+                self.conn.execute("ROLLBACK;")?;
+                return Err(e);
+            }
+        }
+
+        if !written.is_empty() || self.code_changed {
+            let version = read_clock(&self.conn, MEM_VERSION)?.unwrap_or(0) + 1;
+            set_clock(&self.conn, MEM_VERSION, version)?;
+            if read_clock(&self.conn, HISTORY)?.unwrap_or(0) != 0 {
+                let mut stm = self.conn.prepare("INSERT INTO history (version, now, cause, code, writes) VALUES (?, ?, ?, ?, ?);")?;
+                stm.bind(1, version)?;
+                stm.bind(2, &self.now.to_string()[..])?;
+                stm.bind(3, &(bincode::serialize(&self.cause)?)[..])?;
+                match read_source(&self.conn)? {
+                    Some(code) if self.code_changed => { stm.bind(4, &code[..])?; }
+                    _ => { stm.bind(4, ())?; }
+                }
+                stm.bind(5, &(bincode::serialize(&self.writes)?)[..])?;
+                while stm.next()? != sqlite::State::Done { }
+            }
+            let mut stm = self.conn.prepare("INSERT OR REPLACE INTO versions (path, version) VALUES (?, ?);")?;
+            for path in &written {
+                stm.reset()?;
+                stm.bind(1, &(bincode::serialize(path)?)[..])?;
+                stm.bind(2, version)?;
+                while stm.next()? != sqlite::State::Done { }
+            }
+        }
+
+        exec_sqlite_until_not_busy(|| self.conn.execute("COMMIT;"))?;
+        return Ok(());
+    }
+
+}
+
+impl History for SqliteBackend {
+
+    fn history_enabled(&mut self) -> Result<bool, StorageError> {
+        let conn = self.connect()?;
+        return Ok(exec_sqlite_until_not_busy(|| read_clock(&conn, HISTORY))?.unwrap_or(0) != 0);
+    }
+
+    fn current_version(&mut self) -> Result<i64, StorageError> {
+        let conn = self.connect()?;
+        return Ok(exec_sqlite_until_not_busy(|| read_clock(&conn, MEM_VERSION))?.unwrap_or(0));
+    }
+
+    fn set_history(&mut self, on: bool) -> Result<(), StorageError> {
+        let conn = self.connect()?;
+        exec_sqlite_until_not_busy(|| conn.execute("BEGIN IMMEDIATE;"))?;
+        let was_on = read_clock(&conn, HISTORY)?.unwrap_or(0) != 0;
+        if on && !was_on {
+            // NOTE: history starts from a copy of the current version.
+            let mut stm = conn.prepare("INSERT INTO snapshots (version, code, mem) VALUES (?, ?, ?);")?;
+            stm.bind(1, read_clock(&conn, MEM_VERSION)?.unwrap_or(0))?;
+            match read_source(&conn)? {
+                Some(code) => { stm.bind(2, &code[..])?; }
+                None => { stm.bind(2, ())?; }
+            }
+            let mem = load_subtree(&conn, &[])?.unwrap_or(Value::Dict(OrdMap::new()));
+            stm.bind(3, &(bincode::serialize(&mem)?)[..])?;
+            while stm.next()? != sqlite::State::Done { }
+        } else if !on {
+            conn.execute("DELETE FROM history;")?;
+            conn.execute("DELETE FROM snapshots;")?;
+        }
+        set_clock(&conn, HISTORY, if on { 1 } else { 0 })?;
+        exec_sqlite_until_not_busy(|| conn.execute("COMMIT;"))?;
+        return Ok(());
+    }
+
+    fn commits(&mut self, since: i64) -> Result<Vec<Commit>, StorageError> {
+        let conn = self.connect()?;
+        return read_history(&conn, since);
+    }
+
+    fn state_at(&mut self, version: i64) -> Result<(Option<String>, Value), StorageError> {
+        let conn = self.connect()?;
+        exec_sqlite_until_not_busy(|| conn.execute("BEGIN;"))?;
+        if version < 0 || version > read_clock(&conn, MEM_VERSION)?.unwrap_or(0) {
+            return Err(StorageError::NoHistory(version));
+        }
+        let mut stm = conn.prepare("SELECT version, code, mem FROM snapshots WHERE version <= ? ORDER BY version DESC LIMIT 1;")?;
+        stm.bind(1, version)?;
+        let mut image = None;
+        while let sqlite::State::Row = stm.next()? {
+            let mut snapshot = Image::new();
+            snapshot.version = stm.read::<i64>(0)?;
+            snapshot.source = match stm.column_type(1) {
+                sqlite::Type::Null => None,
+                _ => Some(stm.read::<String>(1)?),
+            };
+            snapshot.mem = bincode::deserialize::<Value>(&stm.read::<Vec<u8>>(2)?)?;
+            image = Some(snapshot);
+        }
+        let image = match image {
+            Some(image) => image,
+            None => { return Err(StorageError::NoHistory(version)); }
+        };
+        let commits = read_history(&conn, image.version)?;
+        conn.execute("COMMIT;")?;
+        return image.replay(commits, version);
+    }
+
+}
+
+// The commits recorded in `history` after version `since`, oldest first.
+fn read_history(conn: &sqlite::Connection, since: i64) -> Result<Vec<Commit>, StorageError> {
+    let mut stm = conn.prepare("SELECT version, now, cause, code, writes FROM history WHERE version > ? ORDER BY version;")?;
+    stm.bind(1, since)?;
+    let mut res = Vec::new();
+    while let sqlite::State::Row = stm.next()? {
+        let now = stm.read::<String>(1)?;
+        res.push(Commit {
+            version: stm.read::<i64>(0)?,
+            now: match now.parse::<BigInt>() {
+                Ok(now) => now,
+                Err(_) => { return Err(StorageError::MemoryIsCorrupt(format!("bad timestamp in history: {:?}", now))); }
+            },
+            cause: bincode::deserialize(&stm.read::<Vec<u8>>(2)?)?,
+            code: match stm.column_type(3) {
+                sqlite::Type::Null => None,
+                _ => Some(stm.read::<String>(3)?),
+            },
+            writes: bincode::deserialize(&stm.read::<Vec<u8>>(4)?)?,
+        });
+    }
+    return Ok(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::scratch_path;
+    use crate::syntax::str2blob;
+
+    fn path(keys: &[&str]) -> Vec<Value> {
+        keys.iter().map(|k| Value::Blob(str2blob(k))).collect()
+    }
+
+    fn int(i: i64) -> Value {
+        Value::Int(BigInt::from(i))
+    }
+
+    fn write(s: &mut SqliteBackend, keys: &[&str], i: i64) {
+        let mut tx = s.start_transaction().unwrap();
+        assert!(tx.write_memory(&path(keys), &int(i)).unwrap());
+        tx.commit().unwrap();
+    }
+
+    fn read(s: &mut SqliteBackend, keys: &[&str]) -> Option<Value> {
+        return s.start_transaction().unwrap().read_memory(&path(keys)).unwrap();
+    }
+
+    // An optimistic transaction that reads `read` and then writes `x := 1`,
+    // with a commit that writes `written` in between.
+    fn race(name: &str, read: &[&str], written: &[&str]) -> Result<(), StorageError> {
+        let mut s = SqliteBackend::open(&scratch_path(name)).unwrap();
+        let mut tx = s.start_optimistic().unwrap();
+        tx.read_memory(&path(read)).unwrap();
+        write(&mut s, written, 2);
+        tx.write_memory(&path(&["x"]), &int(1)).unwrap();
+        let res = tx.commit();
+        assert_eq!(read_x(&mut s), if res.is_ok() { Some(int(1)) } else { None });
+        return res;
+    }
+
+    fn read_x(s: &mut SqliteBackend) -> Option<Value> {
+        return read(s, &["x"]);
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_with_a_write_to_what_it_read() {
+        assert!(matches!(race("occ-same", &["a"], &["a"]), Err(StorageError::Conflict)));
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_with_a_write_below_what_it_read() {
+        assert!(matches!(race("occ-below", &["a"], &["a", "b"]), Err(StorageError::Conflict)));
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_with_a_write_above_what_it_read() {
+        assert!(matches!(race("occ-above", &["a", "b"], &["a"]), Err(StorageError::Conflict)));
+    }
+
+    #[test]
+    fn optimistic_commit_ignores_unrelated_writes() {
+        assert!(race("occ-unrelated", &["a", "b"], &["a", "c"]).is_ok());
+    }
+
+    #[test]
+    fn forgotten_reads_are_not_checked() {
+        let mut s = SqliteBackend::open(&scratch_path("occ-forget")).unwrap();
+        let mut tx = s.start_optimistic().unwrap();
+        tx.read_memory(&path(&["a"])).unwrap();
+        tx.forget_reads();
+        write(&mut s, &["a"], 2);
+        tx.write_memory(&path(&["x"]), &int(1)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(read_x(&mut s), Some(int(1)));
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_with_a_new_program() {
+        let mut s = SqliteBackend::open(&scratch_path("occ-code")).unwrap();
+        let mut tx = s.start_optimistic().unwrap();
+        let mut load = s.start_transaction().unwrap();
+        load.replace_code("def a:\n    x := 1;\n").unwrap();
+        load.commit().unwrap();
+        tx.write_memory(&path(&["y"]), &int(1)).unwrap();
+        assert!(matches!(tx.commit(), Err(StorageError::Conflict)));
+    }
+
+    #[test]
+    fn optimistic_commit_conflicts_when_a_write_no_longer_fits() {
+        let mut s = SqliteBackend::open(&scratch_path("occ-store")).unwrap();
+        let mut tx = s.start_optimistic().unwrap();
+        write(&mut s, &["a"], 2);
+        assert!(tx.write_memory(&path(&["a", "b"]), &int(1)).unwrap());
+        assert!(matches!(tx.commit(), Err(StorageError::Conflict)));
+        assert_eq!(read(&mut s, &["a"]), Some(int(2)));
+    }
+
+    fn blob(bytes: &[u8]) -> Value {
+        Value::Blob(bytes.into())
+    }
+
+    fn dict(entries: Vec<(Value, Value)>) -> Value {
+        Value::Dict(entries.into_iter().collect())
+    }
+
+    #[test]
+    fn upgrades_a_v1_database() {
+        let location = scratch_path("migrate-v1");
+        let root = dict(vec![
+            (Value::Blob(str2blob("a")), dict(vec![(Value::Blob(str2blob("b")), int(1))])),
+            (Value::Blob(str2blob("c")), int(2)),
+        ]);
+        let program = "def a:\n    x := 1;\n";
+        {
+            // NOTE: what `open` created before v2.
+            let conn = sqlite::open(&location).unwrap();
+            conn.execute("CREATE TABLE clocks(name TEXT PRIMARY KEY, value INT) WITHOUT ROWID;").unwrap();
+            conn.execute("CREATE TABLE code (source_code TEXT);").unwrap();
+            conn.execute("CREATE TABLE mem (bytes BLOB);").unwrap();
+            set_clock(&conn, "schema_version", 1).unwrap();
+            let mut stm = conn.prepare("INSERT INTO code (source_code) VALUES (?);").unwrap();
+            stm.bind(1, program).unwrap();
+            while stm.next().unwrap() != sqlite::State::Done { }
+            let mut stm = conn.prepare("INSERT INTO mem (bytes) VALUES (?);").unwrap();
+            stm.bind(1, &bincode::serialize(&root).unwrap()[..]).unwrap();
+            while stm.next().unwrap() != sqlite::State::Done { }
+        }
+
+        let mut s = SqliteBackend::open(&location).unwrap();
+        let conn = s.connect().unwrap();
+        assert_eq!(read_clock(&conn, "schema_version").unwrap(), Some(5));
+        assert_eq!(read_clock(&conn, MEM_VERSION).unwrap(), Some(0));
+        assert_eq!(read_clock(&conn, CODE_VERSION).unwrap(), Some(1));
+        assert_eq!(s.history_enabled().unwrap(), false);
+        assert_eq!(read_source(&conn).unwrap().as_deref(), Some(program));
+        assert_eq!(read(&mut s, &[]), Some(root));
+        assert_eq!(read(&mut s, &["a", "b"]), Some(int(1)));
+
+        // NOTE: the upgraded database takes commits like a new one.
+        write(&mut s, &["a", "d"], 3);
+        assert_eq!(read(&mut s, &["a"]), Some(dict(vec![(Value::Blob(str2blob("b")), int(1)), (Value::Blob(str2blob("d")), int(3))])));
+        assert_eq!(read_clock(&conn, MEM_VERSION).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn prefix_end_carries_past_0xff() {
+        assert_eq!(prefix_end(&[0x01, 0x02]), Some(vec![0x01, 0x03]));
+        assert_eq!(prefix_end(&[0x01, 0xff]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0x01, 0xff, 0xff]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+
+    #[test]
+    fn load_subtree_with_0xff_in_keys() {
+        let mut s = SqliteBackend::open(&scratch_path("subtree-ff")).unwrap();
+        let under = dict(vec![(blob(&[0xff]), int(1)), (blob(&[0xff, 0xff]), dict(vec![(blob(&[0xff]), int(2))]))]);
+        let mut tx = s.start_transaction().unwrap();
+        tx.write_memory(&vec![blob(&[0xfe])], &int(3)).unwrap();
+        tx.write_memory(&vec![blob(&[0xff])], &under).unwrap();
+        tx.write_memory(&vec![blob(&[0xff, 0xff])], &int(4)).unwrap();
+        tx.write_memory(&vec![blob(&[0xff, 0xff, 0xff])], &int(5)).unwrap();
+        tx.commit().unwrap();
+
+        let conn = s.connect().unwrap();
+        assert_eq!(load_subtree(&conn, &[blob(&[0xff])]).unwrap(), Some(under));
+        assert_eq!(load_subtree(&conn, &[blob(&[0xff]), blob(&[0xff, 0xff]), blob(&[0xff])]).unwrap(), Some(int(2)));
+        assert_eq!(load_subtree(&conn, &[blob(&[0xff, 0xff])]).unwrap(), Some(int(4)));
+        assert_eq!(load_subtree(&conn, &[blob(&[0xfe, 0xff])]).unwrap(), None);
+
+        // NOTE: replacing a subtree deletes exactly the rows under it.
+        let mut tx = s.start_transaction().unwrap();
+        tx.write_memory(&vec![blob(&[0xff])], &int(6)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(load_subtree(&conn, &[]).unwrap(), Some(dict(vec![
+            (blob(&[0xfe]), int(3)),
+            (blob(&[0xff]), int(6)),
+            (blob(&[0xff, 0xff]), int(4)),
+            (blob(&[0xff, 0xff, 0xff]), int(5)),
+        ])));
+    }
+
+    #[test]
+    fn versions_table_records_the_last_commit_to_each_path() {
+        let mut s = SqliteBackend::open(&scratch_path("occ-versions")).unwrap();
+        write(&mut s, &["a"], 1);
+        write(&mut s, &["b"], 1);
+        write(&mut s, &["a"], 2);
+        let conn = s.connect().unwrap();
+        let version_of = |keys: &[&str]| {
+            let mut stm = conn.prepare("SELECT version FROM versions WHERE path = ?;").unwrap();
+            stm.bind(1, &bincode::serialize(&path(keys)).unwrap()[..]).unwrap();
+            let mut res = None;
+            while let sqlite::State::Row = stm.next().unwrap() {
+                res = Some(stm.read::<i64>(0).unwrap());
+            }
+            res
+        };
+        assert_eq!(version_of(&["a"]), Some(3));
+        assert_eq!(version_of(&["b"]), Some(2));
+        assert_eq!(version_of(&["c"]), None);
+        assert_eq!(read_clock(&conn, MEM_VERSION).unwrap(), Some(3));
+    }
+
+}
@@ -1,4 +1,6 @@
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use im::OrdMap;
 use num_bigint::BigInt;
 use serde::{Serialize,Deserialize};
 
@@ -35,13 +37,52 @@ pub enum TernaryOp {
     IF,
 }
 
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
+pub enum Builtin {
+    // time
+    NOW,
+
+    // nondeterministic
+    RANDOM,
+}
+
+impl Builtin {
+    pub fn lookup(name: &str) -> Option<Builtin> {
+        match name {
+            "now" => Some(Builtin::NOW),
+            "random" => Some(Builtin::RANDOM),
+            _ => None,
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::NOW => 0,
+            Builtin::RANDOM => 2,
+        }
+    }
+
+    /// Impure builtins may produce a different value each time they are
+    /// evaluated, even against the same memory.  (`now()` is fixed for the
+    /// duration of a transaction, so it counts as pure.)
+    pub fn is_pure(self) -> bool {
+        match self {
+            Builtin::NOW => true,
+            Builtin::RANDOM => false,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 #[derive(Serialize, Deserialize)]
 pub enum Value {
     Int(BigInt),
     Bool(bool),
-    Blob(Vec<u8>),
-    Dict(BTreeMap<Value, Value>),
+    // NOTE: cloning a blob or a dict shares it instead of copying it, so
+    // reading even a huge dict out of pmemory is cheap, and changing one entry
+    // only copies the path down to it.
+    Blob(Arc<[u8]>),
+    Dict(OrdMap<Value, Value>),
 }
 
 impl std::fmt::Display for Value {
@@ -50,7 +91,7 @@ impl std::fmt::Display for Value {
             Value::Int(i) => { return i.fmt(f); }
             Value::Bool(b) => { return b.fmt(f); }
             Value::Blob(bytes) => {
-                match String::from_utf8(bytes.clone()) {
+                match String::from_utf8(bytes.to_vec()) {
                     Ok(s) => {
                         // TODO: escaping
                         f.write_str("\"")?;
@@ -83,6 +124,44 @@ impl std::fmt::Display for Value {
     }
 }
 
+/// Types of values, as written in `schema` declarations.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub enum Type {
+    Any,
+    Int,
+    Bool,
+    Blob,
+    Dict(Box<Type>, Box<Type>),
+
+    /// `{name: t, ...}`: a dict with exactly these blob keys.
+    Record(BTreeMap<String, Type>),
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Type::Any => f.write_str("any"),
+            Type::Int => f.write_str("int"),
+            Type::Bool => f.write_str("bool"),
+            Type::Blob => f.write_str("blob"),
+            Type::Dict(k, v) => write!(f, "dict<{}, {}>", k, v),
+            Type::Record(fields) => {
+                f.write_str("{")?;
+                let mut first = true;
+                for (name, t) in fields.iter() {
+                    if first {
+                        first = false;
+                    } else {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", name, t)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
 #[derive(PartialEq, Eq)]
 enum StringParseState {
     INIT,
@@ -128,8 +207,36 @@ pub fn parse_str(s: &str) -> Option<String> {
     return if state == StringParseState::DONE { Option::Some(res) } else { Option::None };
 }
 
-pub fn str2blob(s: &str) -> Vec<u8> {
-    s.to_string().into_bytes()
+/// Parse a duration literal like `30s` into a number of milliseconds.
+pub fn parse_duration_millis(s: &str) -> Option<BigInt> {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit())?;
+    let n = BigInt::parse_bytes(s[..digits_end].as_bytes(), 10)?;
+    let scale = match &s[digits_end..] {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => { return Option::None; }
+    };
+    return Option::Some(n * scale);
+}
+
+/// Parse a block weight like `0.1` or `3` into millionths.
+pub fn parse_weight_millionths(s: &str) -> Option<u64> {
+    let (whole, frac) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i+1..]),
+        None => (s, ""),
+    };
+    if frac.len() > 6 {
+        return Option::None;
+    }
+    let whole = whole.parse::<u64>().ok()?;
+    let frac = format!("{:0<6}", frac).parse::<u64>().ok()?;
+    return whole.checked_mul(1_000_000)?.checked_add(frac);
+}
+
+pub fn str2blob(s: &str) -> Arc<[u8]> {
+    s.as_bytes().into()
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
@@ -138,7 +245,7 @@ pub struct SourceSpan {
     pub end: usize,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum Exp<A> {
     Root(A),
     Name(A, String),
@@ -146,26 +253,157 @@ pub enum Exp<A> {
     Unary(A, UnaryOp, Box<Exp<A>>),
     Binary(A, BinaryOp, Box<Exp<A>>, Box<Exp<A>>),
     Ternary(A, TernaryOp, Box<Exp<A>>, Box<Exp<A>>, Box<Exp<A>>),
+    Call(A, Builtin, Vec<Exp<A>>),
+    Choose(A, String, Box<Exp<A>>, Option<Box<Exp<A>>>),
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+impl<A> Exp<A> {
+    pub fn annotation(&self) -> &A {
+        match self {
+            Exp::Root(a) | Exp::Name(a, _) | Exp::Literal(a, _) => a,
+            Exp::Unary(a, _, _) | Exp::Binary(a, _, _, _) | Exp::Ternary(a, _, _, _, _) => a,
+            Exp::Call(a, _, _) | Exp::Choose(a, _, _, _) => a,
+        }
+    }
+
+    /// True if evaluating this expression twice against the same memory and
+    /// bound names always gives the same answer.  `random(...)` and
+    /// `choose ...` draw from the runner's RNG, so they are not pure.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Exp::Root(_) | Exp::Name(_, _) | Exp::Literal(_, _) => true,
+            Exp::Unary(_, _, e1) => e1.is_pure(),
+            Exp::Binary(_, _, e1, e2) => e1.is_pure() && e2.is_pure(),
+            Exp::Ternary(_, _, e1, e2, e3) => e1.is_pure() && e2.is_pure() && e3.is_pure(),
+            Exp::Call(_, f, args) => f.is_pure() && args.iter().all(|e| e.is_pure()),
+            Exp::Choose(_, _, _, _) => false,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub enum LVal<A> {
     Root(A),
     Name(A, String),
     Index(A, Box<LVal<A>>, Box<Exp<A>>),
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+impl<A> LVal<A> {
+    pub fn annotation(&self) -> &A {
+        match self {
+            LVal::Root(a) | LVal::Name(a, _) | LVal::Index(a, _, _) => a,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub enum Attribute<A> {
+    /// `@every(period)`: the block runs at most once per `period`
+    /// milliseconds.
+    Every(A, Exp<A>),
+
+    /// `@priority(n)`: only the eligible instances with the highest priority
+    /// can run.  Blocks without this attribute have priority 0.
+    Priority(A, Exp<A>),
+
+    /// `@weight(w)`: scales the chance that an instance of the block is picked
+    /// from among those that can run.  The default is 1.  Stored in
+    /// millionths (see `parse_weight_millionths`) so that blocks stay `Ord`.
+    Weight(A, u64),
+
+    /// `@disabled`: the block never runs.
+    Disabled(A),
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub struct Block<A> {
     pub annotation: A,
+    pub attributes: Vec<Attribute<A>>,
     pub name: String,
     pub parameters: Vec<(String, Exp<A>)>,
     pub guards: Vec<Exp<A>>,
     pub assignments: Vec<(LVal<A>, Exp<A>)>,
 }
 
+/// `template name(p1, p2, ...) { blocks }`: blocks that get copied, with the
+/// parameters replaced by arguments, wherever the template is instantiated.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Template<A> {
+    pub annotation: A,
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub blocks: Vec<Block<A>>,
+}
+
+/// `instance name = template(a1, a2, ...);`
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Instance<A> {
+    pub annotation: A,
+    pub name: String,
+    pub template: String,
+    pub arguments: Vec<Exp<A>>,
+}
+
+/// Statements in a `process`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Stmt<A> {
+    /// `step label: require ...; x := ...;` runs atomically, like a block.
+    Step(A, String, Vec<Exp<A>>, Vec<(LVal<A>, Exp<A>)>),
+    While(A, Exp<A>, Vec<Stmt<A>>),
+    If(A, Exp<A>, Vec<Stmt<A>>, Vec<Stmt<A>>),
+    Goto(A, String),
+}
+
+/// `process name(k in m) { statements }`: a sequential program that
+/// desugars into one block per step, guarded by a hidden program counter.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Process<A> {
+    pub annotation: A,
+    pub name: String,
+    pub parameters: Vec<(String, Exp<A>)>,
+    pub body: Vec<Stmt<A>>,
+}
+
+/// Top-level declarations, before desugaring.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Item<A> {
+    Block(Block<A>),
+    Template(Template<A>),
+    Instance(Instance<A>),
+    Process(Process<A>),
+    Init(A, Vec<(LVal<A>, Exp<A>)>),
+    Schema(A, Vec<(String, Type)>),
+
+    /// `const MAX = 100;`
+    Const(A, String, Exp<A>),
+
+    /// `enum State { Idle, Busy }`: constants `State.Idle == "Idle"`, etc.
+    Enum(A, String, Vec<String>),
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Import<A> {
+    pub annotation: A,
+    pub path: String,
+    pub alias: Option<String>,
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Module<A> {
     pub annotation: A,
+    pub imports: Vec<Import<A>>,
     pub blocks: Vec<Block<A>>,
+
+    /// Initial values for pmemory, from the program's `init { ... }`
+    /// sections, in order.
+    pub init: Vec<(LVal<A>, Exp<A>)>,
+
+    /// Declared types of top-level pmemory keys, from the program's
+    /// `schema { ... }` sections.
+    pub schema: BTreeMap<String, Type>,
+
+    /// The values of the program's `const` and `enum` declarations.  These
+    /// are already substituted into the blocks; they are kept so that
+    /// expressions from `ppppl read` and `ppppl write` can use them too.
+    pub constants: BTreeMap<String, Value>,
 }
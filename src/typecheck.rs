@@ -0,0 +1,457 @@
+// Static type checking.
+//
+// The types of memory keys come from the program's `schema` declarations
+// where there are any, and are otherwise inferred from the assignments in the
+// program.  Wherever a type can't be known statically (for instance, the type
+// of `.`) the checker uses `any`, which is compatible with everything.  So,
+// this pass can miss errors, but everything it reports is almost certainly a
+// mistake: either it would fail at runtime or (for comparisons between values
+// of different types) its answer never changes.
+
+use crate::syntax::*;
+
+use std::collections::BTreeMap;
+
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub span: SourceSpan,
+    pub message: String,
+}
+
+type Scope = BTreeMap<String, Type>;
+
+/// The most precise type that is compatible with both `a` and `b`, or `None`
+/// if no value could have both types.
+fn unify(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Any, t) | (t, Type::Any) => Some(t.clone()),
+        (Type::Dict(k1, v1), Type::Dict(k2, v2)) => Some(Type::Dict(Box::new(unify(k1, k2)?), Box::new(unify(v1, v2)?))),
+        (Type::Record(fields), Type::Dict(k, v)) | (Type::Dict(k, v), Type::Record(fields)) => {
+            unify(k, &Type::Blob)?;
+            let mut res = BTreeMap::new();
+            for (name, t) in fields.iter() {
+                res.insert(name.clone(), unify(t, v)?);
+            }
+            Some(Type::Record(res))
+        }
+        (Type::Record(f1), Type::Record(f2)) => {
+            if f1.len() != f2.len() {
+                return None;
+            }
+            let mut res = BTreeMap::new();
+            for (name, t1) in f1.iter() {
+                res.insert(name.clone(), unify(t1, f2.get(name)?)?);
+            }
+            Some(Type::Record(res))
+        }
+        _ if a == b => Some(a.clone()),
+        _ => None,
+    }
+}
+
+fn compatible(a: &Type, b: &Type) -> bool {
+    unify(a, b).is_some()
+}
+
+fn type_of_value(v: &Value) -> Type {
+    match v {
+        Value::Int(_) => Type::Int,
+        Value::Bool(_) => Type::Bool,
+        Value::Blob(_) => Type::Blob,
+        Value::Dict(mapping) => {
+            let mut kt = Type::Any;
+            let mut vt = Type::Any;
+            for (k, v) in mapping.iter() {
+                kt = unify(&kt, &type_of_value(k)).unwrap_or(Type::Any);
+                vt = unify(&vt, &type_of_value(v)).unwrap_or(Type::Any);
+            }
+            Type::Dict(Box::new(kt), Box::new(vt))
+        }
+    }
+}
+
+/// The key and value types of a dict-like type.
+fn dict_parts(t: &Type) -> Option<(Type, Type)> {
+    match t {
+        Type::Any => Some((Type::Any, Type::Any)),
+        Type::Dict(k, v) => Some(((**k).clone(), (**v).clone())),
+        Type::Record(_) => Some((Type::Blob, Type::Any)),
+        _ => None,
+    }
+}
+
+/// If `lv` is `x` or `.["x"]`, possibly followed by one more index, returns
+/// `x` and the index expression.
+fn assigned_key<'a>(lv: &'a LVal<SourceSpan>, scope: &Scope) -> Option<(String, Option<&'a Exp<SourceSpan>>)> {
+    match lv {
+        LVal::Name(_, n) if !scope.contains_key(n) => Some((n.clone(), None)),
+        LVal::Index(_, base, idx) => match (&**base, &**idx) {
+            (LVal::Root(_), Exp::Literal(_, Value::Blob(key))) => Some((String::from_utf8(key.to_vec()).ok()?, None)),
+            (base, idx) => match assigned_key(base, scope)? {
+                (n, None) => Some((n, Some(idx))),
+                _ => None,
+            },
+        },
+        _ => None,
+    }
+}
+
+struct Checker<'a> {
+    schema: &'a BTreeMap<String, Type>,
+
+    // declared or inferred types of memory keys
+    keys: BTreeMap<String, Type>,
+    changed: bool,
+
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+
+    fn error(&mut self, span: SourceSpan, message: String) {
+        self.diagnostics.push(Diagnostic { span: span, message: message });
+    }
+
+    fn expect(&mut self, e: &Exp<SourceSpan>, found: &Type, expected: &Type) {
+        if !compatible(found, expected) {
+            self.error(*e.annotation(), format!("expected {}, but this is {}", expected, found));
+        }
+    }
+
+    fn key_type(&self, key: &str) -> Type {
+        self.keys.get(key).cloned().unwrap_or(Type::Any)
+    }
+
+    /// Learn that `key` holds values of type `t`.
+    fn refine(&mut self, key: String, t: &Type) {
+        if self.schema.contains_key(&key) {
+            return;
+        }
+        let old = self.key_type(&key);
+        if let Some(new) = unify(&old, t) {
+            if new != old {
+                self.keys.insert(key, new);
+                self.changed = true;
+            }
+        }
+    }
+
+    fn index(&mut self, span: SourceSpan, container: &Type, idx: &Exp<SourceSpan>, scope: &Scope) -> Type {
+        let it = self.exp(idx, scope);
+        match (container, idx) {
+            (Type::Record(fields), Exp::Literal(_, Value::Blob(name))) => {
+                match fields.get(&String::from_utf8_lossy(name).into_owned()) {
+                    Some(t) => t.clone(),
+                    None => {
+                        self.error(*idx.annotation(), format!("{} has no such field", container));
+                        Type::Any
+                    }
+                }
+            }
+            _ => match dict_parts(container) {
+                Some((kt, vt)) => {
+                    self.expect(idx, &it, &kt);
+                    vt
+                }
+                None => {
+                    self.error(span, format!("cannot index into {}", container));
+                    Type::Any
+                }
+            },
+        }
+    }
+
+    fn binary(&mut self, span: SourceSpan, op: BinaryOp, e1: &Exp<SourceSpan>, e2: &Exp<SourceSpan>, scope: &Scope) -> Type {
+        if op == BinaryOp::INDEX {
+            if let (Exp::Root(_), Exp::Literal(_, Value::Blob(key))) = (e1, e2) {
+                return self.key_type(&String::from_utf8_lossy(key));
+            }
+            let container = self.exp(e1, scope);
+            return self.index(span, &container, e2, scope);
+        }
+
+        let t1 = self.exp(e1, scope);
+        let t2 = self.exp(e2, scope);
+        match op {
+            BinaryOp::EQ | BinaryOp::NE | BinaryOp::LT | BinaryOp::LE | BinaryOp::GT | BinaryOp::GE => {
+                if !compatible(&t1, &t2) {
+                    self.error(span, format!("comparison between {} and {}", t1, t2));
+                }
+                Type::Bool
+            }
+            BinaryOp::AND | BinaryOp::OR => {
+                self.expect(e1, &t1, &Type::Bool);
+                self.expect(e2, &t2, &Type::Bool);
+                Type::Bool
+            }
+            BinaryOp::PLUS | BinaryOp::MINUS | BinaryOp::TIMES | BinaryOp::DIVIDE | BinaryOp::MOD => {
+                self.expect(e1, &t1, &Type::Int);
+                self.expect(e2, &t2, &Type::Int);
+                Type::Int
+            }
+            BinaryOp::CONCAT => {
+                self.expect(e1, &t1, &Type::Blob);
+                self.expect(e2, &t2, &Type::Blob);
+                Type::Blob
+            }
+            BinaryOp::IN => {
+                match dict_parts(&t2) {
+                    Some((kt, _)) => { self.expect(e1, &t1, &kt); }
+                    None => { self.error(*e2.annotation(), format!("`in` needs a dict, but this is {}", t2)); }
+                }
+                Type::Bool
+            }
+            BinaryOp::INDEX => Type::Any, // handled above
+        }
+    }
+
+    fn exp(&mut self, e: &Exp<SourceSpan>, scope: &Scope) -> Type {
+        match e {
+            Exp::Root(_) => Type::Any,
+            Exp::Name(_, n) => match scope.get(n) {
+                Some(t) => t.clone(),
+                None => self.key_type(n),
+            },
+            Exp::Literal(_, v) => type_of_value(v),
+            Exp::Unary(_, op, e1) => {
+                let t = self.exp(e1, scope);
+                let expected = match op {
+                    UnaryOp::NOT => Type::Bool,
+                    UnaryOp::NEGATE => Type::Int,
+                };
+                self.expect(e1, &t, &expected);
+                expected
+            }
+            Exp::Binary(a, op, e1, e2) => self.binary(*a, *op, e1, e2, scope),
+            Exp::Ternary(_, TernaryOp::IF, cond, e1, e2) => {
+                let ct = self.exp(cond, scope);
+                self.expect(cond, &ct, &Type::Bool);
+                let t1 = self.exp(e1, scope);
+                let t2 = self.exp(e2, scope);
+                unify(&t1, &t2).unwrap_or(Type::Any)
+            }
+            Exp::Call(_, f, args) => {
+                let mut arg_types = Vec::new();
+                for arg in args {
+                    arg_types.push(self.exp(arg, scope));
+                }
+                match f {
+                    Builtin::NOW => Type::Int,
+                    Builtin::RANDOM => {
+                        for (arg, t) in args.iter().zip(arg_types.iter()) {
+                            self.expect(arg, t, &Type::Int);
+                        }
+                        Type::Int
+                    }
+                }
+            }
+            Exp::Choose(_, k, e1, cond) => {
+                let t = self.exp(e1, scope);
+                let kt = match dict_parts(&t) {
+                    Some((kt, _)) => kt,
+                    None => {
+                        self.error(*e1.annotation(), format!("`choose` needs a dict, but this is {}", t));
+                        Type::Any
+                    }
+                };
+                if let Some(cond) = cond {
+                    let mut inner = scope.clone();
+                    inner.insert(k.clone(), kt.clone());
+                    let ct = self.exp(cond, &inner);
+                    self.expect(cond, &ct, &Type::Bool);
+                }
+                kt
+            }
+        }
+    }
+
+    /// The type of values that may be stored at `lv`.
+    fn lval(&mut self, lv: &LVal<SourceSpan>, scope: &Scope) -> Type {
+        match lv {
+            LVal::Root(_) => Type::Any,
+            LVal::Name(a, n) => {
+                if scope.contains_key(n) {
+                    self.error(*a, format!("cannot assign to parameter `{}`", n));
+                    return Type::Any;
+                }
+                self.key_type(n)
+            }
+            LVal::Index(a, base, idx) => match (&**base, &**idx) {
+                (LVal::Root(_), Exp::Literal(_, Value::Blob(key))) => self.key_type(&String::from_utf8_lossy(key)),
+                _ => {
+                    let container = self.lval(base, scope);
+                    self.index(*a, &container, idx, scope)
+                }
+            },
+        }
+    }
+
+    fn assign(&mut self, lv: &LVal<SourceSpan>, e: &Exp<SourceSpan>, scope: &Scope) {
+        let vt = self.exp(e, scope);
+        let expected = self.lval(lv, scope);
+        if !compatible(&vt, &expected) {
+            self.error(*e.annotation(), format!("this is {}, but it is assigned to a location that holds {}", vt, expected));
+            return;
+        }
+
+        match assigned_key(lv, scope) {
+            Some((key, None)) => { self.refine(key, &vt); }
+            Some((key, Some(idx))) => {
+                if let Type::Record(_) = self.key_type(&key) {
+                    return;
+                }
+                let kt = self.exp(idx, scope);
+                self.refine(key, &Type::Dict(Box::new(kt), Box::new(vt)));
+            }
+            None => { }
+        }
+    }
+
+    fn block(&mut self, b: &Block<SourceSpan>) {
+        let mut scope = Scope::new();
+        for (p, e) in &b.parameters {
+            let t = self.exp(e, &scope);
+            let kt = match dict_parts(&t) {
+                Some((kt, _)) => kt,
+                None => {
+                    self.error(*e.annotation(), format!("parameter `{}` ranges over the keys of a dict, but this is {}", p, t));
+                    Type::Any
+                }
+            };
+            scope.insert(p.clone(), kt);
+        }
+
+        for attr in &b.attributes {
+            match attr {
+                Attribute::Every(_, e) | Attribute::Priority(_, e) => {
+                    let t = self.exp(e, &scope);
+                    self.expect(e, &t, &Type::Int);
+                }
+                Attribute::Weight(_, _) | Attribute::Disabled(_) => { }
+            }
+        }
+
+        for g in &b.guards {
+            let t = self.exp(g, &scope);
+            self.expect(g, &t, &Type::Bool);
+        }
+
+        for (lv, e) in &b.assignments {
+            self.assign(lv, e, &scope);
+        }
+    }
+
+    fn module(&mut self, m: &Module<SourceSpan>) {
+        for (lv, e) in &m.init {
+            self.assign(lv, e, &Scope::new());
+        }
+        for b in &m.blocks {
+            self.block(b);
+        }
+    }
+
+}
+
+/// Type-check a module, returning a list of problems (empty if the module is
+/// well-typed).
+pub fn check(m: &Module<SourceSpan>) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        schema: &m.schema,
+        keys: m.schema.clone(),
+        changed: true,
+        diagnostics: Vec::new(),
+    };
+
+    // Types learned from one assignment can affect the types of expressions
+    // anywhere else, so iterate to a fixed point.  Only the diagnostics from
+    // the last pass count.  (Types only ever get more precise, so this always
+    // terminates, but cap it anyway to keep deeply nested dicts cheap.)
+    let mut passes = 0;
+    while checker.changed && passes < 20 {
+        checker.changed = false;
+        checker.diagnostics.clear();
+        checker.module(m);
+        passes += 1;
+    }
+
+    // NOTE: some subexpressions are checked twice, so drop repeats.
+    checker.diagnostics.sort_by(|d1, d2| (d1.span, &d1.message).cmp(&(d2.span, &d2.message)));
+    checker.diagnostics.dedup_by(|d1, d2| d1.span == d2.span && d1.message == d2.message);
+    return checker.diagnostics;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ModuleParser;
+
+    // The problems with `source`, as (the code each one points at, message).
+    fn errors(source: &str) -> Vec<(String, String)> {
+        let module = ModuleParser::new().parse(source).unwrap();
+        return check(&module).into_iter().map(|d| (source[d.span.start .. d.span.end].to_string(), d.message)).collect();
+    }
+
+    fn error(code: &str, message: &str) -> Vec<(String, String)> {
+        vec![(code.to_string(), message.to_string())]
+    }
+
+    #[test]
+    fn well_typed_programs_are_accepted() {
+        assert!(errors(include_str!("../examples/count.pppl")).is_empty());
+        assert!(errors(include_str!("../examples/div3.pppl")).is_empty());
+        assert!(errors(include_str!("../examples/bigdict.pppl")).is_empty());
+        assert!(errors("
+schema {
+    accounts: dict<blob, {balance: int, open: bool}>;
+}
+
+init {
+    accounts := {};
+    names := {};
+}
+
+def open(n in names):
+    require !(n in accounts);
+    accounts[n] := {};
+    accounts[n][\"balance\"] := 0;
+    accounts[n][\"open\"] := true;
+
+def pay:
+    require accounts != {};
+    accounts[choose a in accounts where accounts[a][\"open\"]][\"balance\"] := random(1, 10);
+").is_empty());
+    }
+
+    #[test]
+    fn operands_of_the_wrong_type_are_reported() {
+        assert_eq!(errors("def f:\n    x := 1 + true;\n"), error("true", "expected int, but this is bool"));
+        assert_eq!(errors("def f:\n    require 1;\n"), error("1", "expected bool, but this is int"));
+        assert_eq!(errors("def f:\n    x := -\"a\";\n"), error("\"a\"", "expected int, but this is blob"));
+    }
+
+    #[test]
+    fn comparisons_that_never_change_are_reported() {
+        assert_eq!(errors("init {\n    x := 0;\n}\n\ndef f:\n    require x == \"a\";\n    x := 1;\n"), error("x == \"a\"", "comparison between int and blob"));
+    }
+
+    #[test]
+    fn bad_indexing_is_reported() {
+        assert_eq!(errors("init {\n    x := 0;\n}\n\ndef f:\n    y := x[1];\n"), error("x[1]", "cannot index into int"));
+        assert_eq!(errors("init {\n    x := 0;\n}\n\ndef f:\n    require 1 in x;\n"), error("x", "`in` needs a dict, but this is int"));
+        assert_eq!(errors("init {\n    x := 0;\n}\n\ndef f:\n    y := choose k in x;\n"), error("x", "`choose` needs a dict, but this is int"));
+        assert_eq!(errors("init {\n    x := 0;\n}\n\ndef f(k in x):\n    y := k;\n"), error("x", "parameter `k` ranges over the keys of a dict, but this is int"));
+        assert_eq!(errors("schema {\n    r: {a: int};\n}\n\ndef f:\n    y := r[\"b\"];\n"), error("\"b\"", "{a: int} has no such field"));
+    }
+
+    #[test]
+    fn assignments_that_dont_fit_are_reported() {
+        assert_eq!(errors("schema {\n    x: int;\n}\n\ndef f:\n    x := \"a\";\n"), error("\"a\"", "this is blob, but it is assigned to a location that holds int"));
+    }
+
+    #[test]
+    fn types_are_inferred_from_assignments_anywhere_in_the_program() {
+        // The use comes before the assignment that says what `x` holds.
+        assert_eq!(errors("def f:\n    require x;\n\ndef g:\n    x := 1;\n"), error("x", "expected bool, but this is int"));
+    }
+}